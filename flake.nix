@@ -57,6 +57,8 @@
               rustToolchain
               openssl
               pkg-config
+              # For the optional heif feature
+              libheif
               cargo-deny
               cargo-edit
               cargo-watch
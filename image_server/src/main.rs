@@ -1,250 +1,5090 @@
 use axum::{
-    body::Body,
-    extract::State,
-    http::{header, Response, StatusCode},
+    body::{Body, Bytes},
+    extract::{self, ConnectInfo, Query, RawQuery, Request, State},
+    extract::ws::{Message, WebSocket, WebSocketUpgrade},
+    http::{header, HeaderMap, HeaderName, HeaderValue, Method, Response, StatusCode, Uri},
+    middleware::{self, Next},
     response::{IntoResponse, Response as AxumResponse},
-    routing::get,
+    routing::{get, post},
     Router,
 };
-use std::sync::Arc;
-use std::net::SocketAddr;
+use std::collections::{BTreeMap, HashMap, HashSet, VecDeque};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+use std::hash::{DefaultHasher, Hash, Hasher};
+use std::sync::{Arc, Mutex, RwLock};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::num::NonZeroUsize;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use arc_swap::ArcSwap;
+use notify::RecommendedWatcher;
+use axum_server::tls_rustls::RustlsConfig;
+use base64::{engine::general_purpose::STANDARD as BASE64, Engine};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
 use tokio::net::TcpListener;
+use tokio::sync::{broadcast, Semaphore, SemaphorePermit};
+use tokio_util::io::ReaderStream;
+use tower_http::compression::{predicate::DefaultPredicate, CompressionLayer};
+use tower_http::cors::{AllowOrigin, Any, CorsLayer};
+use tower_http::limit::RequestBodyLimitLayer;
+use tower_http::request_id::{MakeRequestUuid, PropagateRequestIdLayer, RequestId, SetRequestIdLayer};
+use tower_http::timeout::TimeoutLayer;
+use tower_http::trace::{DefaultOnResponse, TraceLayer};
+use tower_http::LatencyUnit;
 
-use image::{ImageReader, ImageFormat};
+use image::{DynamicImage, ImageDecoder, ImageEncoder, ImageReader, ImageFormat, RgbImage};
+use image::codecs::avif::AvifEncoder;
+use image::codecs::jpeg::JpegEncoder;
+use image::codecs::png::PngEncoder;
+use image::codecs::webp::WebPEncoder;
+use image::imageops::FilterType;
+use image::metadata::Orientation;
 
-use std::io::{self, Cursor};
-use std::path::Path;
-use std::fs::{self, File, DirEntry};
+use std::io::{self, BufReader, Cursor, Read};
+use std::path::{Path, PathBuf};
+use std::fs::{self, File};
 
-use rand::Rng;
+use rand::{Rng, SeedableRng};
+use rand::rngs::StdRng;
+use rand::seq::SliceRandom;
 
-use log::{info, error, LevelFilter};
-use simplelog::{CombinedLogger, Config, WriteLogger};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use lru::LruCache;
+use path_set::PathSet;
+use rate_limit::RateLimiter;
+use rayon::prelude::*;
 
-use serde::Deserialize;
+use tracing::{debug, info, error, instrument, warn, Level, Span};
+use tracing::field::Empty;
+use tracing_subscriber::filter::LevelFilter;
+use tracing_subscriber::fmt::format::FmtSpan;
+use tracing_subscriber::fmt::MakeWriter;
+use tracing_subscriber::layer::{Layered, SubscriberExt};
+use tracing_subscriber::reload;
+use tracing_subscriber::util::SubscriberInitExt;
+use tracing_subscriber::{Layer, Registry};
+
+use metrics::{counter, gauge, histogram};
+use metrics_exporter_prometheus::{PrometheusBuilder, PrometheusHandle};
+
+use serde::{Deserialize, Serialize};
 use clap::Parser;
 
+mod path_set;
+mod rate_limit;
+mod watcher;
+
 #[derive(Parser, Debug)]
 #[command(version, about, long_about = None)]
 struct Args {
+    /// Config file, may be repeated with later files overriding keys of earlier
+    /// ones. Values missing from all of them fall back to NAS_* environment variables
+    #[arg(long)]
+    config: Vec<String>,
+    /// Path of the log file, missing parent directories are created
+    #[arg(long, default_value = "nas_server.log")]
+    log_file: PathBuf,
+    /// Also write log output to stdout
+    #[arg(long)]
+    stdout: bool,
+    /// Override the listen address from the config file
+    #[arg(long)]
+    addr: Option<IpAddr>,
+    /// Override the listen port from the config file
+    #[arg(long)]
+    port: Option<u16>,
+    /// Scan the media directories, report what would be served and exit
+    #[arg(long)]
+    dry_run: bool,
+    /// With --dry-run, also print every discovered path
+    #[arg(long, requires = "dry_run")]
+    list_paths: bool,
+    /// Decode the discovered images before serving and exit if too many fail
     #[arg(long)]
-    config: String,
-    log: String,
+    verify: bool,
+    /// With --verify, decode only this many randomly chosen images instead of all
+    #[arg(long, requires = "verify")]
+    verify_sample: Option<usize>,
+    /// With --verify, the percentage of failed decodes still accepted
+    #[arg(long, requires = "verify", default_value_t = 0.0)]
+    verify_max_failed_percent: f64,
 }
 
 const IMAGE_EXTENSION: [&str; 3] = ["png", "jpg", "jpeg"];
+#[cfg(feature = "heif")]
+const HEIF_EXTENSION: [&str; 2] = ["heic", "heif"];
+const MAX_RESOLUTION: u32 = 4096;
+const DEFAULT_LIST_LIMIT: usize = 100;
+const MAX_LIST_LIMIT: usize = 1000;
+const DEFAULT_JPEG_QUALITY: u8 = 80;
+/// Lowest JPEG quality a byte budget may push an encode down to.
+const MIN_BUDGET_QUALITY: u8 = 20;
+/// AVIF encoder effort, 1 slowest to 10 fastest.
+const AVIF_SPEED: u8 = 4;
+const BUDGET_QUALITY_STEP: u8 = 10;
+const IMAGE_ID_HEADER: &str = "x-image-id";
+const IMAGE_PATH_HEADER: &str = "x-image-path";
+const QUALITY_USED_HEADER: &str = "x-quality-used";
+const SOURCE_FORMAT_HEADER: &str = "x-source-format";
+const MAX_SRCSET_SIZES: usize = 16;
+/// Most columns, and rows, a /mosaic may have.
+const MAX_MOSAIC_SIDE: u32 = 6;
+/// Side of the thumbnail an image is shrunk to before averaging its color.
+const COLOR_SAMPLE_SIDE: u32 = 16;
+const PUSH_CHANNEL_CAPACITY: usize = 16;
+/// Random picks redrawn at most this often for resembling a recent one.
+const MAX_SIMILAR_REDRAWS: usize = 8;
+/// Images whose longer side is at most this percent longer count as square.
+const SQUARE_TOLERANCE_PERCENT: u64 = 5;
+/// The watermark is scaled to fit in this fraction of each thumbnail side.
+const WATERMARK_SCALE: u32 = 5;
 #[tokio::main]
 async fn main() {
     let args = Args::parse();
-    CombinedLogger::init(
-        vec![
-            WriteLogger::new(
-                LevelFilter::Info,
-                Config::default(),
-                File::create(args.log + "nas_server.log").unwrap()
-            ),
-        ]
-    ).unwrap();
-    
-    let media_confg = MediaConfig::new(&args.config).unwrap(); 
+    // Logging is not set up yet, so problems go straight to stderr.
+    let media_confg = load_config(&args).unwrap_or_else(|e| {
+        eprintln!("{}", e);
+        std::process::exit(1);
+    });
+
+    let log_level = init_logging(&args, &media_confg);
+    media_confg.log_sources();
+    if let Some(threads) = media_confg.limits.cpu_threads {
+        rayon::ThreadPoolBuilder::new()
+            .num_threads(threads)
+            .build_global()
+            .expect("rayon thread pool is built only once");
+        info!("Limited parallel scanning and decoding to {} threads", threads);
+    }
+    // Lets the `image` crate open HEIC/HEIF files through libheif.
+    #[cfg(feature = "heif")]
+    {
+        libheif_rs::integration::image::register_heic_decoding_hook();
+        libheif_rs::integration::image::register_heif_decoding_hook();
+    }
+
+    if args.dry_run {
+        match MediaState::new(media_confg) {
+            Ok(state) => {
+                if args.verify && !verify_images(&state, &args) {
+                    std::process::exit(1);
+                }
+                let (count, images) = state.list_images(0, usize::MAX);
+                if args.list_paths {
+                    images.iter().for_each(|(_, img_path)| println!("{}", img_path));
+                }
+                println!("Found {} images", count);
+            }
+            Err(e) => {
+                eprintln!("Failed to load media {}", e);
+                std::process::exit(1);
+            }
+        }
+        return;
+    }
+
+    let metrics_handle = PrometheusBuilder::new()
+        .install_recorder()
+        .expect("failed to install Prometheus recorder");
 
     match MediaState::new(media_confg) {
         Ok(state) => {
-            let addr = state.media_config.network.clone(); 
-            info!(" Server started, listening on http://{}", addr);
-            let listener = TcpListener::bind(addr).await.unwrap();
-            
+            if args.verify && !verify_images(&state, &args) {
+                std::process::exit(1);
+            }
+            let addr = state.media_config().network;
+            let tls = state.media_config().tls.clone();
+            let listen_uds = state.media_config().listen_uds.clone();
+
             let shared_state = Arc::new(state);
-            let app = Router::new()
-                .route("/get_random_art", get(get_random_art_handler))
-                .with_state(shared_state);
-            axum::serve(listener, app).await.unwrap();
+            shared_state.prune_disk_cache();
+            let watcher = start_watcher(&shared_state);
+            #[cfg(unix)]
+            tokio::spawn(reload_on_sighup(args, shared_state.clone(), watcher, log_level));
+            #[cfg(not(unix))]
+            let _watcher = (watcher, log_level);
+            if shared_state.media_config().image.warm_cache {
+                tokio::spawn(warm_cache(shared_state.clone()));
+            }
+            if shared_state.media_config().push.interval_secs > 0 {
+                tokio::spawn(push_images(shared_state.clone()));
+            }
+            if shared_state.media_config().scrub.interval_secs > 0 {
+                tokio::spawn(scrub_images(shared_state.clone()));
+            }
+            let app = build_router(shared_state, metrics_handle);
+            match (tls, listen_uds) {
+                (Some(tls), _) => serve_tls(addr, app, &tls).await,
+                #[cfg(unix)]
+                (None, Some(socket_path)) => serve_uds(&socket_path, app).await,
+                _ => {
+                    info!(" Server started, listening on http://{}", addr);
+                    let listener = TcpListener::bind(addr).await.unwrap();
+                    axum::serve(listener, app.into_make_service_with_connect_info::<SocketAddr>())
+                        .with_graceful_shutdown(shutdown_signal())
+                        .await
+                        .unwrap();
+                }
+            }
         }
         Err(e) => error!("Failed to load media {}", e),
     }
 }
 
+/// Serves `app` on a Unix domain socket at `socket_path`, replacing a stale
+/// socket left by an earlier run and removing it again on shutdown. There is
+/// no client address, so rate limiting does not apply to these connections.
+#[cfg(unix)]
+async fn serve_uds(socket_path: &Path, app: Router) {
+    use std::os::unix::fs::FileTypeExt;
+    use hyper_util::rt::{TokioExecutor, TokioIo};
+    use hyper_util::server::conn::auto;
+    use hyper_util::server::graceful::GracefulShutdown;
+    use hyper_util::service::TowerToHyperService;
+
+    if fs::symlink_metadata(socket_path).is_ok_and(|metadata| metadata.file_type().is_socket()) {
+        fs::remove_file(socket_path).unwrap();
+    }
+    let listener = tokio::net::UnixListener::bind(socket_path).unwrap();
+    info!(" Server started, listening on unix:{}", socket_path.display());
+
+    let builder = auto::Builder::new(TokioExecutor::new());
+    let graceful = GracefulShutdown::new();
+    let shutdown = shutdown_signal();
+    tokio::pin!(shutdown);
+    loop {
+        tokio::select! {
+            accepted = listener.accept() => {
+                let stream = match accepted {
+                    Ok((stream, _)) => stream,
+                    Err(e) => {
+                        // Usually out of file descriptors, give connections time to close.
+                        warn!("Failed to accept on {}: {}", socket_path.display(), e);
+                        tokio::time::sleep(Duration::from_secs(1)).await;
+                        continue;
+                    }
+                };
+                let connection = builder
+                    .serve_connection_with_upgrades(TokioIo::new(stream), TowerToHyperService::new(app.clone()))
+                    .into_owned();
+                let connection = graceful.watch(connection);
+                tokio::spawn(async move {
+                    if let Err(e) = connection.await {
+                        debug!("Connection on unix socket ended with an error: {}", e);
+                    }
+                });
+            }
+            _ = &mut shutdown => break,
+        }
+    }
+
+    drop(listener);
+    if let Err(e) = fs::remove_file(socket_path) {
+        warn!("Failed to remove socket {}: {}", socket_path.display(), e);
+    }
+    graceful.shutdown().await;
+}
+
+/// Reads the config file with the command line overrides applied.
+fn load_config(args: &Args) -> Result<MediaConfig, String> {
+    let mut media_config = MediaConfig::new(&args.config)?;
+    if let Some(addr) = args.addr {
+        media_config.network.set_ip(addr);
+    }
+    if let Some(port) = args.port {
+        media_config.network.set_port(port);
+    }
+    media_config.validate()?;
+    Ok(media_config)
+}
+
+/// A manifest is the whole collection, files appearing on disk are not added.
+fn start_watcher(state: &Arc<MediaState>) -> Option<RecommendedWatcher> {
+    if state.media_config().manifest.is_some() {
+        return None;
+    }
+    watcher::watch_media(state.clone())
+        .map_err(|e| error!("Failed to watch media directory: {}", e))
+        .ok()
+}
+
+/// Re-reads the config file on every SIGHUP. A config that fails to load or
+/// validate is logged and ignored, otherwise it replaces the running one.
+#[cfg(unix)]
+async fn reload_on_sighup(
+    args: Args,
+    state: Arc<MediaState>,
+    mut watcher: Option<RecommendedWatcher>,
+    log_level: LogLevelHandle,
+) {
+    let mut hangups = match tokio::signal::unix::signal(tokio::signal::unix::SignalKind::hangup()) {
+        Ok(hangups) => hangups,
+        Err(e) => {
+            error!("Failed to install SIGHUP handler, config reloading is disabled: {}", e);
+            return;
+        }
+    };
+    while hangups.recv().await.is_some() {
+        info!("Received SIGHUP, reloading config");
+        let media_config = match load_config(&args) {
+            Ok(media_config) => {
+                media_config.log_sources();
+                media_config
+            }
+            Err(e) => {
+                error!("Keeping the current config, the new one is invalid: {}", e);
+                continue;
+            }
+        };
+        let old = state.media_config();
+        if let Err(e) = log_level.reload(media_config.log_level) {
+            error!("Failed to change log level: {}", e);
+        }
+        let rewatch = old.media != media_config.media || old.manifest != media_config.manifest;
+        let state = state.clone();
+        // Rescanning walks the whole collection.
+        watcher = tokio::task::spawn_blocking(move || {
+            state.apply_config(media_config);
+            if rewatch {
+                // The old watcher has to go first, or both would report changes.
+                drop(watcher);
+                start_watcher(&state)
+            } else {
+                watcher
+            }
+        })
+            .await
+            .expect("config reload task panicked");
+    }
+}
+
+/// Sends log output to the log file, and to stdout with `--stdout`, formatted
+/// according to `log_format`. Dependencies logging through `log` are forwarded too.
+/// The returned handle changes the level later on.
+fn init_logging(args: &Args, media_config: &MediaConfig) -> LogLevelHandle {
+    if let Some(log_dir) = args.log_file.parent().filter(|dir| !dir.as_os_str().is_empty()) {
+        fs::create_dir_all(log_dir).unwrap();
+    }
+    let log_file = Arc::new(File::create(&args.log_file).unwrap());
+    let mut layers = vec![log_layer(media_config.log_format, log_file)];
+    if args.stdout {
+        layers.push(log_layer(media_config.log_format, io::stdout));
+    }
+    let (log_level, handle) = reload::Layer::new(media_config.log_level);
+    tracing_subscriber::registry()
+        .with(layers)
+        .with(log_level)
+        .init();
+    handle
+}
+
+type LogLevelHandle = reload::Handle<
+    LevelFilter,
+    Layered<Vec<Box<dyn Layer<Registry> + Send + Sync>>, Registry>>;
+
+fn log_layer<W>(format: LogFormat, writer: W) -> Box<dyn Layer<Registry> + Send + Sync>
+where
+    W: for<'writer> MakeWriter<'writer> + Send + Sync + 'static,
+{
+    // Closing spans report their fields and timings, e.g. the picked image of a request.
+    let layer = tracing_subscriber::fmt::layer()
+        .with_writer(writer)
+        .with_ansi(false)
+        .with_span_events(FmtSpan::CLOSE);
+    match format {
+        LogFormat::Plain => layer.boxed(),
+        // One object per line with the message next to level and timestamp.
+        LogFormat::Json => layer.json().flatten_event(true).boxed(),
+    }
+}
+
+async fn serve_tls(addr: SocketAddr, app: Router, tls: &TlsConfig) {
+    // Only the ring backend is compiled in, make it the process-wide default.
+    let _ = rustls::crypto::ring::default_provider().install_default();
+    let rustls_config = RustlsConfig::from_pem_file(&tls.cert, &tls.key)
+        .await
+        .unwrap();
+
+    let handle = axum_server::Handle::new();
+    let shutdown_handle = handle.clone();
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        shutdown_handle.graceful_shutdown(None);
+    });
+
+    info!(" Server started, listening on https://{}", addr);
+    axum_server::bind_rustls(addr, rustls_config)
+        .handle(handle)
+        .serve(app.into_make_service_with_connect_info::<SocketAddr>())
+        .await
+        .unwrap();
+}
+
+fn build_router(state: Arc<MediaState>, metrics_handle: PrometheusHandle) -> Router {
+    let mut image_routes = Router::new()
+        .route("/get_random_art", get(get_random_art_handler))
+        .route("/get_image/:id", get(get_image_handler))
+        .route("/next", get(next_handler))
+        .route("/daily", get(daily_handler))
+        .route("/mosaic", get(mosaic_handler))
+        .route("/channel/:name/random", get(channel_random_handler))
+        .route("/count", get(count_handler))
+        .route("/list", get(list_handler))
+        .route("/search", get(search_handler))
+        .route("/metadata/:id", get(metadata_handler))
+        .route("/srcset/:id", get(srcset_handler))
+        .route("/color/:id", get(color_handler))
+        .route("/original/:id", get(original_handler))
+        .route("/reload", post(reload_handler))
+        .route("/favorite/:id", post(add_favorite_handler).delete(remove_favorite_handler))
+        .route("/get_random_favorite", get(get_random_favorite_handler))
+        .route("/block/:id", post(block_handler).delete(unblock_handler))
+        .route("/blocked", get(blocked_handler))
+        .route("/history", get(history_handler))
+        .route("/stats", get(stats_handler));
+    if state.media_config().push.interval_secs > 0 {
+        image_routes = image_routes.route("/ws", get(ws_handler));
+    }
+    let image_routes = image_routes
+        .route_layer(middleware::from_fn_with_state(state.clone(), require_api_key))
+        .route_layer(middleware::from_fn_with_state(state.clone(), limit_rate));
+
+    let cors = cors_layer(&state.media_config().cors);
+    let request_timeout = state.media_config().server.request_timeout_secs;
+    let max_body_bytes = state.media_config().server.max_body_bytes;
+    let request_id_header = HeaderName::from_bytes(state.media_config().server.request_id_header.as_bytes())
+        .expect("request_id_header is checked by validate");
+
+    // Probes, metrics and the version stay reachable without credentials.
+    let mut router = Router::new()
+        .merge(image_routes)
+        .route("/healthz", get(healthz_handler))
+        .route("/readyz", get(readyz_handler))
+        .route("/version", get(version_handler))
+        .route("/metrics", get(move || async move { metrics_handle.render() }))
+        .fallback(fallback_handler)
+        .with_state(state)
+        // Covers every route, so endpoints added later are guarded by default.
+        .layer(RequestBodyLimitLayer::new(max_body_bytes));
+
+    if request_timeout > 0 {
+        router = router.layer(TimeoutLayer::with_status_code(
+            StatusCode::REQUEST_TIMEOUT,
+            Duration::from_secs(request_timeout)));
+    }
+    // The default predicate skips image/* bodies, which are compressed already.
+    router = router.layer(CompressionLayer::new().compress_when(DefaultPredicate::new()));
+
+    // The CORS layer wraps everything so preflight requests are answered
+    // before they reach the API key check.
+    if let Some(cors) = cors {
+        router = router.layer(cors);
+    }
+    // Every request gets a span and its latency covers all other layers.
+    router = router.layer(TraceLayer::new_for_http()
+        .make_span_with(request_span)
+        .on_response(DefaultOnResponse::new().level(Level::INFO).latency_unit(LatencyUnit::Millis)));
+    // Outermost, so the span already sees the id and even errors carry it back.
+    router
+        .layer(PropagateRequestIdLayer::new(request_id_header.clone()))
+        .layer(SetRequestIdLayer::new(request_id_header, MakeRequestUuid))
+}
+
+/// The span every log line of a request is recorded in, tagged with the id
+/// the client sent or the one generated for it.
+fn request_span(request: &Request) -> Span {
+    let request_id = request.extensions().get::<RequestId>()
+        .and_then(|id| id.header_value().to_str().ok())
+        .unwrap_or_default();
+    tracing::info_span!("request",
+        method = %request.method(),
+        uri = %request.uri(),
+        version = ?request.version(),
+        request_id = %request_id)
+}
+
+fn cors_layer(config: &CorsConfig) -> Option<CorsLayer> {
+    if config.allowed_origins.is_empty() {
+        return None;
+    }
+
+    let allow_origin = if config.allowed_origins.iter().any(|origin| origin == "*") {
+        AllowOrigin::any()
+    } else {
+        let origins: Vec<HeaderValue> = config.allowed_origins.iter()
+            .filter_map(|origin| HeaderValue::from_str(origin)
+                .map_err(|_| error!("Ignoring invalid CORS origin '{}'", origin))
+                .ok())
+            .collect();
+        AllowOrigin::list(origins)
+    };
+
+    Some(
+        CorsLayer::new()
+            .allow_origin(allow_origin)
+            .allow_methods([Method::GET, Method::POST, Method::DELETE])
+            .allow_headers(Any)
+            .expose_headers(Any)
+    )
+}
+
+async fn require_api_key(
+    State(state): State<Arc<MediaState>>,
+    request: Request,
+    next: Next,
+) -> AxumResponse {
+    if let Some(api_key) = &state.media_config().auth.api_key {
+        let provided = request.headers()
+            .get("x-api-key")
+            .map(|value| value.as_bytes())
+            .unwrap_or_default();
+        if !constant_time_eq(provided, api_key.as_bytes()) {
+            return ImageError::Unauthorized.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+async fn limit_rate(
+    State(state): State<Arc<MediaState>>,
+    request: Request,
+    next: Next,
+) -> AxumResponse {
+    if let Some(limiter) = &state.rate_limiter {
+        let client = request.extensions()
+            .get::<ConnectInfo<SocketAddr>>()
+            .map(|ConnectInfo(addr)| addr.ip());
+        if client.is_some_and(|ip| !limiter.check(ip)) {
+            return ImageError::RateLimited.into_response();
+        }
+    }
+    next.run(request).await
+}
+
+/// Pre-renders the default thumbnail of every image into `cache_dir`, in JPEG
+/// and each format browsers may negotiate, a few images at a time so live
+/// requests still get a decoder.
+async fn warm_cache(state: Arc<MediaState>) {
+    if state.cache_dir.is_none() {
+        warn!("warm_cache is enabled but no cache_dir is configured, skipping");
+        return;
+    }
+
+    let specs: Vec<ThumbnailSpec> = [OutputFormat::default()].into_iter()
+        .chain(OutputFormat::NEGOTIATED)
+        .map(|format| state.media_config().image.default_spec(format))
+        .collect();
+    let images = state.index.read().unwrap().images.clone();
+    let total = images.len();
+    let concurrency = state.media_config().image.warm_concurrency.max(1);
+    let semaphore = Arc::new(Semaphore::new(concurrency));
+    let warmed = Arc::new(AtomicUsize::new(0));
+    info!("Warming thumbnail cache for {} images", total);
+
+    for image in images {
+        let permit = semaphore.clone().acquire_owned().await.unwrap();
+        let state = state.clone();
+        let warmed = warmed.clone();
+        let specs = specs.clone();
+        tokio::task::spawn_blocking(move || {
+            for spec in specs {
+                if let Err(e) = state.disk_thumbnail(&image, spec) {
+                    warn!("Failed to warm {:?} thumbnail for {}: {}", spec.format, image.path, e);
+                    break;
+                }
+            }
+            let done = warmed.fetch_add(1, Ordering::Relaxed) + 1;
+            if done.is_multiple_of(500) {
+                info!("Warmed {}/{} thumbnails", done, total);
+            }
+            drop(permit);
+        });
+    }
+
+    let _ = semaphore.acquire_many(concurrency as u32).await;
+    info!("Finished warming thumbnail cache, {} images", total);
+}
+
+/// Sends the next slideshow pick to every /ws client each `interval_secs`, so
+/// all connected frames switch at the same moment.
+async fn push_images(state: Arc<MediaState>) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(state.media_config().push.interval_secs));
+    loop {
+        ticks.tick().await;
+        // Without listeners the slideshow is left where it is.
+        if state.pushes.receiver_count() == 0 {
+            continue;
+        }
+        let Some((id, image)) = state.get_next_image() else {
+            continue;
+        };
+        state.record_served(&image.path);
+        match serde_json::to_string(&ListEntry::new(id, &image.path)) {
+            // Sending only fails once every client has disconnected meanwhile.
+            Ok(message) => {
+                let _ = state.pushes.send(message);
+            }
+            Err(e) => error!("Failed to serialize pushed image: {}", e),
+        }
+    }
+}
+
+/// Re-decodes a batch of images every `scrub.interval_secs`, walking the whole
+/// collection over time so files that went bad on disk show up in the log.
+async fn scrub_images(state: Arc<MediaState>) {
+    let mut ticks = tokio::time::interval(Duration::from_secs(state.media_config().scrub.interval_secs));
+    let mut cursor = 0;
+    loop {
+        ticks.tick().await;
+        let state = state.clone();
+        cursor = tokio::task::spawn_blocking(move || state.scrub_batch(cursor))
+            .await
+            .expect("scrub task panicked");
+    }
+}
+
+/// Fully decodes all images, or `--verify-sample` random ones, and reports how
+/// many failed. Returns false when that is more than `--verify-max-failed-percent`.
+fn verify_images(state: &MediaState, args: &Args) -> bool {
+    let mut images = state.index.read().unwrap().images.clone();
+    if let Some(sample) = args.verify_sample {
+        images.shuffle(&mut rand::thread_rng());
+        images.truncate(sample);
+    }
+    info!("Verifying {} images", images.len());
+
+    let limits = &state.media_config().limits;
+    let failed = images.par_iter()
+        .filter(|image| match decode_image(&image.path, limits) {
+            Ok(_) => false,
+            Err(e) => {
+                warn!("Verify failed for {}: {}", image.path, e);
+                true
+            }
+        })
+        .count();
+    let failed_percent = if images.is_empty() {
+        0.0
+    } else {
+        failed as f64 * 100.0 / images.len() as f64
+    };
+    let summary = format!("Verified {} images, {} failed ({:.1}%)", images.len(), failed, failed_percent);
+    println!("{}", summary);
+    if failed_percent > args.verify_max_failed_percent {
+        error!("{}, more than the accepted {}%", summary, args.verify_max_failed_percent);
+        return false;
+    }
+    info!("{}", summary);
+    true
+}
+
+/// Compares two byte strings without short-circuiting on the first mismatch.
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+async fn shutdown_signal() {
+    let ctrl_c = async {
+        tokio::signal::ctrl_c()
+            .await
+            .expect("failed to install Ctrl+C handler");
+    };
+
+    #[cfg(unix)]
+    let terminate = async {
+        tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())
+            .expect("failed to install SIGTERM handler")
+            .recv()
+            .await;
+    };
+
+    #[cfg(not(unix))]
+    let terminate = std::future::pending::<()>();
+
+    tokio::select! {
+        _ = ctrl_c => {},
+        _ = terminate => {},
+    }
+    info!("Shutting down gracefully");
+}
+
 enum ImageError {
     IO(std::io::Error),
     Load(image::ImageError),
+    TooLarge(image::ImageError),
     Encode(image::ImageError),
+    NotFound(usize),
+    UnknownChannel(String),
+    BadRequest(String),
+    Unauthorized,
+    RateLimited,
+    NoMatch,
+    Unavailable,
+    Overloaded,
+}
+
+impl ImageError {
+    fn kind(&self) -> &'static str {
+        match self {
+            ImageError::IO(_) => "io",
+            ImageError::Load(_) => "load",
+            ImageError::TooLarge(_) => "limits",
+            ImageError::Encode(_) => "encode",
+            ImageError::NotFound(_) | ImageError::UnknownChannel(_) => "not_found",
+            ImageError::BadRequest(_) => "bad_request",
+            ImageError::Unauthorized => "unauthorized",
+            ImageError::RateLimited => "rate_limited",
+            ImageError::NoMatch => "no_match",
+            ImageError::Unavailable => "unavailable",
+            ImageError::Overloaded => "overloaded",
+        }
+    }
+
+    /// The file's own data is broken, rather than the read failing or the image
+    /// being over the limits, so trying again cannot help.
+    fn is_corrupt(&self) -> bool {
+        matches!(self, ImageError::Load(_))
+    }
+
+    fn status(&self) -> StatusCode {
+        match self {
+            ImageError::IO(_) | ImageError::Load(_) | ImageError::Encode(_) =>
+                StatusCode::INTERNAL_SERVER_ERROR,
+            ImageError::TooLarge(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            ImageError::NotFound(_) | ImageError::UnknownChannel(_) => StatusCode::NOT_FOUND,
+            ImageError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            ImageError::Unauthorized => StatusCode::UNAUTHORIZED,
+            ImageError::RateLimited => StatusCode::TOO_MANY_REQUESTS,
+            ImageError::NoMatch => StatusCode::NOT_FOUND,
+            ImageError::Unavailable | ImageError::Overloaded => StatusCode::SERVICE_UNAVAILABLE,
+        }
+    }
+}
+
+impl std::fmt::Display for ImageError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ImageError::IO(e) => write!(f, "Failed during IO image: {}", e),
+            ImageError::Load(e) => write!(f, "Failed to load image: {}", e),
+            ImageError::TooLarge(e) => write!(f, "Image exceeds decode limits: {}", e),
+            ImageError::Encode(e) => write!(f, "Failed to encode Image: {}", e),
+            ImageError::NotFound(id) => write!(f, "No image with id: {}", id),
+            ImageError::UnknownChannel(name) => write!(f, "No channel named: {}", name),
+            ImageError::BadRequest(msg) => write!(f, "Bad request: {}", msg),
+            ImageError::Unauthorized => write!(f, "Missing or invalid API key"),
+            ImageError::RateLimited => write!(f, "Too many requests"),
+            ImageError::NoMatch => write!(f, "No image matches the requested filters"),
+            ImageError::Unavailable => write!(f, "No images available"),
+            ImageError::Overloaded => write!(f, "Too many images are being decoded, try again later"),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorResponse<'a> {
+    error: String,
+    kind: &'a str,
 }
 
 impl IntoResponse for ImageError {
     fn into_response(self) -> AxumResponse {
-        let (status, message) = match self {
-            ImageError::IO(e) => {
-                let error_msg = format!("Failed during IO image: {}", e);
-                error!("{}",error_msg);
-                (StatusCode::INTERNAL_SERVER_ERROR, error_msg)
-            }
-            ImageError::Load(e) => {
-                let error_msg = format!("Failed to load image: {}", e);
-                error!("{}",error_msg);
-                (StatusCode::INTERNAL_SERVER_ERROR, error_msg)
-            }
-            ImageError::Encode(e) => {
-                let error_msg = format!("Failed to encode Image: {}", e);
-                error!("{}",error_msg);
-                (StatusCode::INTERNAL_SERVER_ERROR, error_msg)
-            }
+        let error_msg = self.to_string();
+        error!(kind = self.kind(), "{}", error_msg);
+        counter!("nas_errors_total", "kind" => self.kind()).increment(1);
+        json_response(self.status(), &ErrorResponse { error: error_msg, kind: self.kind() })
+    }
+}
+
+#[derive(Serialize)]
+struct RouteNotFoundResponse<'a> {
+    error: &'a str,
+    kind: &'a str,
+    path: &'a str,
+}
+
+/// Answers unknown routes in the same JSON shape as every other error.
+async fn fallback_handler(uri: Uri) -> AxumResponse {
+    debug!("No route for {}", uri.path());
+    counter!("nas_errors_total", "kind" => "not_found").increment(1);
+    json_response(StatusCode::NOT_FOUND, &RouteNotFoundResponse {
+        error: "not found",
+        kind: "not_found",
+        path: uri.path(),
+    })
+}
+
+fn json_response<T: Serialize>(status: StatusCode, body: &T) -> AxumResponse {
+    match serde_json::to_vec(body) {
+        Ok(bytes) => (
+            status,
+            [(header::CONTENT_TYPE, "application/json")],
+            bytes,
+        ).into_response(),
+        Err(e) => {
+            error!("Failed to serialize response: {}", e);
+            StatusCode::INTERNAL_SERVER_ERROR.into_response()
+        }
+    }
+}
+
+/// Decides which files found on disk end up in the served collection.
+#[derive(Clone, Debug)]
+pub struct ScanFilter {
+    extensions: Vec<String>,
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    follow_symlinks: bool,
+    dedupe: bool,
+    max_depth: Option<usize>,
+    min_width: Option<u32>,
+    min_height: Option<u32>,
+    ignore_dirs: Vec<String>,
+}
+
+impl ScanFilter {
+    /// Invalid glob patterns are left out and added to `problems`.
+    pub fn new(image: &ImageConfig, scan: &ScanConfig, problems: &mut Vec<String>) -> Self {
+        let include = if scan.include.is_empty() {
+            None
+        } else {
+            Some(build_globset("scan.include", &scan.include, problems))
+        };
+        ScanFilter {
+            extensions: image.extensions.clone(),
+            include,
+            exclude: build_globset("scan.exclude", &scan.exclude, problems),
+            follow_symlinks: scan.follow_symlinks,
+            dedupe: scan.dedupe,
+            max_depth: scan.max_depth,
+            min_width: scan.min_width,
+            min_height: scan.min_height,
+            ignore_dirs: scan.ignore_dirs.clone(),
+        }
+    }
+
+    /// Dotfiles, dot directories and directories named in `ignore_dirs`, such
+    /// as the thumbnails a Synology keeps in `@eaDir`.
+    fn is_ignored(&self, path: &Path) -> bool {
+        path.file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.starts_with('.') || self.ignore_dirs.iter().any(|dir| dir == name))
+    }
+
+    /// Whether `image` is at least `min_width` by `min_height`. Images of
+    /// unknown size are kept.
+    fn is_large_enough(&self, image: &ImageEntry) -> bool {
+        image.dimensions.is_none_or(|(width, height)| {
+            self.min_width.is_none_or(|min_width| width >= min_width)
+                && self.min_height.is_none_or(|min_height| height >= min_height)
+        })
+    }
+
+    /// Whether the contents of a directory `depth` levels below a media root
+    /// are scanned, the root itself being at depth 0.
+    fn scans_depth(&self, depth: usize) -> bool {
+        self.max_depth.is_none_or(|max_depth| depth < max_depth)
+    }
+
+    fn has_image_extension(&self, file_path: &Path) -> bool {
+        let Some(extension) = file_path.extension().and_then(|extension| extension.to_str()) else {
+            return false;
         };
-        (status, message.to_string()).into_response()
+        self.extensions.iter()
+            .any(|allowed| allowed.trim_start_matches('.').eq_ignore_ascii_case(extension))
+    }
+
+    fn is_excluded(&self, path: &Path) -> bool {
+        self.exclude.is_match(path)
+    }
+
+    fn is_included(&self, path: &Path) -> bool {
+        self.include.as_ref().is_none_or(|include| include.is_match(path))
+    }
+}
+
+fn build_globset(key: &str, patterns: &[String], problems: &mut Vec<String>) -> GlobSet {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        match Glob::new(pattern) {
+            Ok(glob) => {
+                builder.add(glob);
+            }
+            Err(e) => problems.push(format!("{} pattern '{}' is invalid: {}", key, pattern, e)),
+        }
     }
+    builder.build().unwrap_or_else(|e| {
+        problems.push(format!("{} patterns could not be built: {}", key, e));
+        GlobSet::empty()
+    })
 }
 
-fn get_canonical_path_if_image(entry: &DirEntry) -> Option<String> {
-    let file_path = entry.path();
+fn get_canonical_path_if_image(file_path: &Path, filter: &ScanFilter) -> Option<String> {
     if !file_path.is_file() {
         return None;
     }
 
-    let extension = file_path.extension()?
-        .to_str()?
-        .to_lowercase();
+    if !filter.has_image_extension(file_path) {
+        debug!("Skipping file with unsupported extension {:?}", file_path);
+        return None;
+    }
 
-    if IMAGE_EXTENSION.contains(&extension.as_str()) {
-        fs::canonicalize(file_path)
-            .ok()
-            .and_then(|path_buf| path_buf.to_str().map(|s| s.to_string()))
-    } else {
-         None
+    let canonical = fs::canonicalize(file_path).ok()?;
+    if filter.is_excluded(&canonical) || !filter.is_included(&canonical) {
+        debug!("Skipping file filtered by glob patterns {:?}", canonical);
+        return None;
     }
+    canonical.to_str().map(|s| s.to_string())
 }
 
 fn find_images_recursively(
     current_path: &Path,
-    paths_accumulator: &mut Vec<String>) -> io::Result<()> {
-    if !current_path.is_dir() {
-        return Ok(());
+    filter: &ScanFilter,
+    visited: &Mutex<HashSet<PathBuf>>,
+    skipped: &AtomicUsize,
+    depth: usize) -> io::Result<Vec<String>> {
+    if !current_path.is_dir() || !filter.scans_depth(depth) {
+        return Ok(Vec::new());
     }
 
-    for entry_result in fs::read_dir(current_path)? {
-        let entry = entry_result?;
-        let path = entry.path();
-        
-        if path.is_dir() {
-            if let Err(e) = find_images_recursively(&path, paths_accumulator) {
-                error!("Error accessing subdirectory {:?}: {}", path, e);
-            }
-        } else if let Some(image_paths) = get_canonical_path_if_image(&entry) {
-            paths_accumulator.push(image_paths);
-        }
+    if filter.is_excluded(current_path) {
+        debug!("Skipping directory excluded by glob patterns {:?}", current_path);
+        return Ok(Vec::new());
     }
-    Ok(())
 
-}
+    // A symlink pointing back up the tree would otherwise be walked forever.
+    if filter.follow_symlinks
+        && !visited.lock().unwrap().insert(fs::canonicalize(current_path)?) {
+        warn!("Skipping already visited directory {:?}, likely a symlink cycle", current_path);
+        return Ok(Vec::new());
+    }
+
+    let entries = fs::read_dir(current_path)?
+        .map(|entry_result| entry_result.map(|entry| entry.path()))
+        .collect::<io::Result<Vec<PathBuf>>>()?;
 
-fn find_absolute_image_path(directory_path: &Path) -> Result<Vec<String>, std::io::Error> {
-    let mut image_paths = Vec::new();
-    find_images_recursively(directory_path, &mut image_paths)?;
+    // Subdirectories are traversed and canonicalized on the rayon pool. Collecting
+    // keeps read_dir order, which the OS does not sort, index_order = "path" does.
+    let image_paths = entries
+        .par_iter()
+        .flat_map_iter(|path| {
+            if filter.is_ignored(path) {
+                skipped.fetch_add(1, Ordering::Relaxed);
+                return Vec::new();
+            }
+            if path.is_dir() {
+                if path.is_symlink() && !filter.follow_symlinks {
+                    debug!("Skipping symlinked directory {:?}", path);
+                    return Vec::new();
+                }
+                find_images_recursively(path, filter, visited, skipped, depth + 1).unwrap_or_else(|e| {
+                    error!("Error accessing subdirectory {:?}: {}", path, e);
+                    Vec::new()
+                })
+            } else {
+                get_canonical_path_if_image(path, filter).into_iter().collect()
+            }
+        })
+        .collect();
     Ok(image_paths)
 }
 
-#[derive(Clone)]
-pub struct MediaState {
-    media_config: MediaConfig,
-    paths: Vec<String>
+/// Images under `directory_path`, which lies `depth` levels below its media root.
+fn find_absolute_image_path(
+    directory_path: &Path,
+    filter: &ScanFilter,
+    depth: usize) -> Result<Vec<String>, std::io::Error> {
+    let skipped = AtomicUsize::new(0);
+    let found = find_images_recursively(
+        directory_path, filter, &Mutex::new(HashSet::new()), &skipped, depth)?;
+    let skipped = skipped.into_inner();
+    if skipped > 0 {
+        info!("Skipped {} hidden or ignored entries under {:?}", skipped, directory_path);
+    }
+    Ok(found)
 }
 
-impl MediaState {
-    pub fn new(media_config: MediaConfig) -> Result<Self, String> {
-        let directory_path = Path::new(&media_config.media);
+fn scan_media_dir(media_dir: &str, filter: &ScanFilter) -> Result<Vec<String>, String> {
+    let directory_path = Path::new(media_dir);
+
+    if !directory_path.is_dir() {
+        return Err(format!("Error: Path is not a directory: {}", media_dir));
+    }
 
-        if !directory_path.is_dir() {
-            return Err(format!("Error: Path is not a directory: {}", &media_config.media));
+    match find_absolute_image_path(directory_path, filter, 0) {
+        Ok(paths) if !paths.is_empty() => Ok(paths),
+        Ok(_) => Err(format!("Directory does not contain images: {}", media_dir)),
+        Err(e) => Err(format!("Failed to scan directory {}: {}", media_dir, e)),
+    }
+}
+
+/// Collapses files with identical contents to the lexicographically first path,
+/// keeping scan order otherwise. Files that cannot be read are kept as they are.
+fn dedupe_by_content(paths: Vec<String>) -> Vec<String> {
+    let hashed: Vec<(String, Option<blake3::Hash>)> = paths
+        .into_par_iter()
+        .map(|img_path| {
+            let hash = File::open(&img_path)
+                .and_then(|file| blake3::Hasher::new().update_reader(file).map(|hasher| hasher.finalize()))
+                .map_err(|e| warn!("Failed to hash {} for dedupe: {}", img_path, e))
+                .ok();
+            (img_path, hash)
+        })
+        .collect();
+
+    let mut kept_by_hash: HashMap<blake3::Hash, &str> = HashMap::new();
+    for (img_path, hash) in &hashed {
+        if let Some(hash) = hash {
+            let kept = kept_by_hash.entry(*hash).or_insert(img_path);
+            if img_path.as_str() < *kept {
+                *kept = img_path;
+            }
         }
+    }
 
-        match find_absolute_image_path(directory_path) {
-            Ok(paths) => if !paths.is_empty() {
-                    Ok(MediaState{media_config, paths })
-                } else {
-                Err(format!("Directory does not contain images: {}", &media_config.media))
-            },
-            Err(_e) => Err(
-                format!("No supoorted image found in directory: {}", &media_config.media)
-            )
+    let unique: Vec<String> = hashed.iter()
+        .filter(|(img_path, hash)| hash.is_none_or(|hash| kept_by_hash[&hash] == img_path))
+        .map(|(img_path, _)| img_path.clone())
+        .collect();
+    info!("Dedupe removed {} duplicate images", hashed.len() - unique.len());
+    unique
+}
+
+/// Reads the newline-delimited path list at `manifest`, one image per line.
+/// Blank lines and `#` comments are skipped, relative paths are taken from the
+/// manifest's directory. Nothing is checked on disk, missing files only fail
+/// when they are served.
+fn read_manifest(manifest: &Path, filter: &ScanFilter) -> Result<Vec<String>, String> {
+    let contents = fs::read_to_string(manifest)
+        .map_err(|e| format!("Failed to read manifest {}: {}", manifest.display(), e))?;
+    let base = manifest.parent().unwrap_or(Path::new(""));
+    let paths = contents.lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(|line| base.join(line))
+        .filter(|path| filter.has_image_extension(path)
+            && !filter.is_excluded(path) && filter.is_included(path))
+        .filter_map(|path| path.to_str().map(|s| s.to_string()))
+        .collect();
+    Ok(paths)
+}
+
+/// Collects the entries to serve, from the manifest when one is configured
+/// and by scanning every media directory otherwise.
+fn scan_images(media_config: &MediaConfig) -> Vec<ImageEntry> {
+    if let Some(manifest) = &media_config.manifest {
+        let mut paths = read_manifest(manifest, &media_config.scan_filter)
+            .unwrap_or_else(|e| {
+                error!("{}", e);
+                Vec::new()
+            });
+        let mut seen = HashSet::new();
+        paths.retain(|img_path| seen.insert(img_path.clone()));
+        info!("Loaded {} images from manifest {}", paths.len(), manifest.display());
+        return paths.into_iter().map(ImageEntry::unchecked).collect();
+    }
+
+    let mut paths = Vec::new();
+    for media_dir in &media_config.media {
+        match scan_media_dir(media_dir, &media_config.scan_filter) {
+            Ok(found) => paths.extend(found),
+            Err(e) => error!("{}", e),
         }
     }
 
-    pub fn image_count(&self) -> usize {
-        self.paths.len()
+    // Overlapping media directories would otherwise list an image twice.
+    let mut seen = HashSet::new();
+    paths.retain(|img_path| seen.insert(img_path.clone()));
+    if media_config.scan_filter.dedupe {
+        paths = dedupe_by_content(paths);
+    }
+    // Ids are positions, sorting keeps them the same across restarts.
+    if matches!(media_config.index_order, IndexOrder::Path) {
+        paths.sort_unstable();
     }
+    let found = paths.len();
+    let images: Vec<ImageEntry> = paths.into_par_iter()
+        .map(|img_path| ImageEntry::new(img_path).with_dimensions(&media_config.limits))
+        .filter(|image| media_config.scan_filter.is_large_enough(image))
+        .collect();
+    if images.len() < found {
+        info!("Skipped {} images below min_width/min_height", found - images.len());
+    }
+    images.into_par_iter()
+        .map(|image| image.with_phash(media_config))
+        .collect()
+}
 
-    pub fn get_random_image(&self) -> &str {
-        let image_count = self.image_count();
-        let random_index = rand::thread_rng().gen_range(0..image_count);
-        &self.paths[random_index]
+/// Difference hash of the image at `img_path`: one bit per horizontally adjacent
+/// pixel pair of a 9x8 grayscale downscale, so similar shots differ in few bits.
+fn perceptual_hash(img_path: &str, limits: &LimitsConfig) -> Result<u64, ImageError> {
+    let small = decode_image(img_path, limits)?
+        .thumbnail_exact(9, 8)
+        .into_luma8();
+    let mut hash = 0;
+    for y in 0..8 {
+        for x in 0..8 {
+            let brighter = small.get_pixel(x, y)[0] > small.get_pixel(x + 1, y)[0];
+            hash = hash << 1 | u64::from(brighter);
+        }
     }
+    Ok(hash)
 }
 
-async fn get_random_art_handler(
-    State(state): State<Arc<MediaState>>,
-) -> Result<impl IntoResponse, ImageError> {
-    let img_path = state.get_random_image();
-    let img = ImageReader::open(Path::new(img_path)).map_err(ImageError::IO)?
-        .with_guessed_format().map_err(ImageError::IO)?
-        .decode().map_err(ImageError::Load)?;
-    
-    let resolution: u32 = state.media_config.image.resolution;
-    let thumb = img.thumbnail(
-        resolution,
-        resolution);
-    let mut buffer = Cursor::new(Vec::new());
-    thumb.write_to(&mut buffer, ImageFormat::Jpeg)
-        .map_err(ImageError::Encode)?;
+/// How many directories `path` lies below the media root containing it. Paths
+/// outside every root are treated as roots themselves.
+fn depth_below_root(roots: &[PathBuf], path: &Path) -> usize {
+    roots.iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .map_or(0, |relative| relative.components().count())
+}
 
-    Ok(
-        Response::builder()
-            .status(StatusCode::OK)
-            .header(header::CONTENT_TYPE, "image/jpeg")
-            .body(Body::from(buffer.into_inner()))
-            .unwrap()
-    )
+/// Whether `path`, or a directory between it and its media root, is ignored.
+fn is_ignored_below_root(roots: &[PathBuf], path: &Path, filter: &ScanFilter) -> bool {
+    roots.iter()
+        .find_map(|root| path.strip_prefix(root).ok())
+        .is_some_and(|relative| relative.ancestors().any(|ancestor| filter.is_ignored(ancestor)))
 }
 
-#[derive(Debug, Deserialize)]
-pub struct NetworkConfigRaw {
-    pub addr: [u8; 4], 
-    pub port: u16,
+/// Scanned paths are canonical, so the roots have to be too for grouping.
+fn media_roots(media_config: &MediaConfig) -> Vec<PathBuf> {
+    media_config.media.iter()
+        .filter_map(|media_dir| fs::canonicalize(media_dir).ok())
+        .collect()
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct ImageConfig {
-    pub resolution: u32,
+/// Shuffled playlist of image indices, every image is visited once before
+/// the order is reshuffled.
+#[derive(Default)]
+struct SlideshowState {
+    order: Vec<usize>,
+    cursor: usize,
 }
 
-#[derive(Debug, Deserialize)]
-pub struct MediaConfigRaw {
-    #[serde(rename = "media_dir")]
-    pub media: String,
-    pub network: NetworkConfigRaw,
-    pub image: ImageConfig,
+impl SlideshowState {
+    fn next_index(&mut self, image_count: usize, rng: &mut impl Rng) -> usize {
+        // The collection may have changed size since the last shuffle.
+        if self.cursor >= self.order.len() || self.order.len() != image_count {
+            self.order = (0..image_count).collect();
+            self.order.shuffle(rng);
+            self.cursor = 0;
+        }
+        let index = self.order[self.cursor];
+        self.cursor += 1;
+        index
+    }
 }
 
-#[derive(Clone, Debug, Deserialize)]
-pub struct MediaConfig {
-    pub media: String,
-    pub network: SocketAddr,
-    pub image: ImageConfig,
+/// Encoded thumbnails kept in memory, least recently used first out. Bounded
+/// by `cache_size` entries and by `cache_max_bytes` in total, since a single
+/// 4096 pixel PNG can outweigh a hundred default thumbnails.
+struct ThumbnailCache {
+    entries: LruCache<(String, ThumbnailSpec), Thumbnail>,
+    bytes: usize,
+    max_bytes: usize,
 }
 
-impl MediaConfig {
-    pub fn new( path: &str) -> Result<Self, String> {
-        let contents = std::fs::read_to_string(path)
-            .map_err(
-                |e| format!("Could not read config file '{}': {}", path, e))?;
-        let raw_config: MediaConfigRaw = toml::from_str(&contents)
-            .map_err(
-                |e| format!(
-                    "Could not parse TOML from file '{}': {}", path, e))?;
-        let network_socket = SocketAddr::from(
-            (raw_config.network.addr, raw_config.network.port));
+impl ThumbnailCache {
+    fn new(capacity: NonZeroUsize, max_bytes: usize) -> Self {
+        ThumbnailCache { entries: LruCache::new(capacity), bytes: 0, max_bytes }
+    }
 
-        Ok(MediaConfig {
-            media: raw_config.media,
-            network: network_socket,  
-            image: raw_config.image,
-        })
+    fn get(&mut self, key: &(String, ThumbnailSpec)) -> Option<Thumbnail> {
+        self.entries.get(key).cloned()
+    }
+
+    /// Thumbnails larger than the whole budget are not kept at all, they would
+    /// only push out everything else.
+    fn put(&mut self, key: (String, ThumbnailSpec), thumbnail: Thumbnail) {
+        let size = thumbnail.bytes.len();
+        if size > self.max_bytes {
+            return;
+        }
+        // Either the previous value under `key` or the entry evicted for room.
+        if let Some((_, replaced)) = self.entries.push(key, thumbnail) {
+            self.bytes -= replaced.bytes.len();
+        }
+        self.bytes += size;
+        while self.bytes > self.max_bytes {
+            let Some((_, evicted)) = self.entries.pop_lru() else {
+                break;
+            };
+            self.bytes -= evicted.bytes.len();
+        }
+    }
+
+    fn remove_path(&mut self, img_path: &str) {
+        let stale: Vec<_> = self.entries.iter()
+            .filter(|((path, _), _)| path == img_path)
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in stale {
+            if let Some(removed) = self.entries.pop(&key) {
+                self.bytes -= removed.bytes.len();
+            }
+        }
+    }
+
+    fn clear(&mut self) {
+        self.entries.clear();
+        self.bytes = 0;
+    }
+}
+
+/// Average colors by path, with the modification time they were computed at.
+type ColorCache = Mutex<HashMap<String, (Option<SystemTime>, [u8; 3])>>;
+
+/// An encoded thumbnail with the JPEG quality a byte budget settled on.
+#[derive(Clone)]
+struct Thumbnail {
+    bytes: Bytes,
+    /// Only known for thumbnails encoded under `max_bytes`.
+    quality: Option<u8>,
+}
+
+/// A served image with the file details captured when it was scanned, so
+/// requests do not have to stat the file again.
+#[derive(Clone, Debug)]
+pub struct ImageEntry {
+    pub path: String,
+    pub modified: Option<SystemTime>,
+    pub size: Option<u64>,
+    /// Perceptual hash, only computed when `near_duplicate_distance` is set.
+    pub phash: Option<u64>,
+    /// Width and height as served, after EXIF rotation. Unknown for manifest
+    /// entries and files whose header could not be read.
+    pub dimensions: Option<(u32, u32)>,
+}
+
+impl ImageEntry {
+    fn new(path: String) -> Self {
+        ImageEntry::unchecked(path).with_metadata()
+    }
+
+    /// An entry whose file has not been looked at yet, as listed in a manifest.
+    fn unchecked(path: String) -> Self {
+        ImageEntry { path, modified: None, size: None, phash: None, dimensions: None }
+    }
+
+    /// Reads the dimensions from the file's header.
+    fn with_dimensions(mut self, limits: &LimitsConfig) -> Self {
+        self.dimensions = image_dimensions(&self.path, limits)
+            .map_err(|e| warn!("Failed to read the size of {}: {}", self.path, e))
+            .ok();
+        self
+    }
+
+    /// Fills in the details of an unchecked entry from the file.
+    fn with_metadata(mut self) -> Self {
+        if self.modified.is_none() && self.size.is_none() {
+            let metadata = fs::metadata(&self.path).ok();
+            self.modified = metadata.as_ref().and_then(|metadata| metadata.modified().ok());
+            self.size = metadata.map(|metadata| metadata.len());
+        }
+        self
+    }
+
+    /// Decodes the file for its perceptual hash when near-duplicates are avoided.
+    /// Manifest entries skip this, so they are never treated as near-duplicates.
+    fn with_phash(mut self, media_config: &MediaConfig) -> Self {
+        if media_config.image.near_duplicate_distance.is_some() {
+            self.phash = perceptual_hash(&self.path, &media_config.limits)
+                .map_err(|e| warn!("Failed to hash {} for near-duplicates: {}", self.path, e))
+                .ok();
+        }
+        self
+    }
+}
+
+/// Scanned images, whose positions are the ids served by the API, grouped by
+/// the top-level folder they sit in under their media directory.
+struct MediaIndex {
+    images: Vec<ImageEntry>,
+    roots: Vec<PathBuf>,
+    folders: BTreeMap<PathBuf, Vec<usize>>,
+}
+
+/// The first directory below the media root containing `img_path`, or the
+/// root itself for images stored directly in it.
+fn top_level_folder(roots: &[PathBuf], img_path: &str) -> PathBuf {
+    let img_path = Path::new(img_path);
+    let Some(root) = roots.iter().find(|root| img_path.starts_with(root)) else {
+        return img_path.parent().map(Path::to_path_buf).unwrap_or_default();
+    };
+    let relative = img_path.strip_prefix(root).unwrap_or(img_path);
+    let mut components = relative.components();
+    match (components.next(), components.next()) {
+        (Some(folder), Some(_)) => root.join(folder),
+        _ => root.clone(),
+    }
+}
+
+impl MediaIndex {
+    fn new(images: Vec<ImageEntry>, roots: Vec<PathBuf>) -> Self {
+        let mut index = MediaIndex { images, roots, folders: BTreeMap::new() };
+        index.regroup();
+        index
+    }
+
+    fn folder_of(&self, img_path: &str) -> PathBuf {
+        top_level_folder(&self.roots, img_path)
+    }
+
+    /// Top-level folders called `name`, one per media root that has it.
+    fn channel_folders(&self, name: &str) -> HashSet<PathBuf> {
+        self.folders.keys()
+            .filter(|folder| !self.roots.contains(folder))
+            .filter(|folder| folder.file_name().is_some_and(|folder_name| folder_name == name))
+            .cloned()
+            .collect()
+    }
+
+    fn regroup(&mut self) {
+        let mut folders: BTreeMap<PathBuf, Vec<usize>> = BTreeMap::new();
+        for (index, image) in self.images.iter().enumerate() {
+            folders.entry(self.folder_of(&image.path)).or_default().push(index);
+        }
+        self.folders = folders;
+    }
+
+    fn push(&mut self, image: ImageEntry) {
+        let folder = self.folder_of(&image.path);
+        self.folders.entry(folder).or_default().push(self.images.len());
+        self.images.push(image);
+    }
+
+    fn random_index(&self, weighting: Weighting, rng: &mut impl Rng) -> usize {
+        match weighting {
+            Weighting::Uniform => rng.gen_range(0..self.images.len()),
+            Weighting::ByFolder => {
+                let folder = self.folders.values()
+                    .nth(rng.gen_range(0..self.folders.len()))
+                    .expect("folder index is in range");
+                folder[rng.gen_range(0..folder.len())]
+            }
+        }
+    }
+}
+
+pub struct MediaState {
+    /// Swapped as a whole when the config file is re-read on SIGHUP.
+    media_config: ArcSwap<MediaConfig>,
+    index: RwLock<MediaIndex>,
+    cache: Option<Mutex<ThumbnailCache>>,
+    /// `cache_dir` as created at startup, a reloaded config does not move it.
+    cache_dir: Option<PathBuf>,
+    last_random_index: AtomicUsize,
+    /// Perceptual hashes of the latest random picks, newest at the front.
+    recent_phashes: Mutex<VecDeque<u64>>,
+    slideshow: Mutex<SlideshowState>,
+    rng: Mutex<StdRng>,
+    favorites: PathSet,
+    blocked: PathSet,
+    /// Paths of the most recently served picks, newest at the front.
+    history: Mutex<VecDeque<String>>,
+    rate_limiter: Option<RateLimiter>,
+    /// Images pushed to /ws clients, as JSON text.
+    pushes: broadcast::Sender<String>,
+    /// Bounds simultaneous decodes, `None` when `max_concurrent_decodes` is unset.
+    decodes: Option<Semaphore>,
+    /// Decoded once from `watermark_path` and scaled per thumbnail.
+    watermark: Option<DynamicImage>,
+    /// Hash of the watermark file as loaded, so replacing the logo, or changing
+    /// `watermark_path` for the next restart, never reuses stale thumbnails.
+    watermark_digest: Option<blake3::Hash>,
+    /// The day number and path of the current image of the day.
+    daily: Mutex<Option<(u64, String)>>,
+    colors: ColorCache,
+}
+
+impl MediaState {
+    pub fn new(media_config: MediaConfig) -> Result<Self, String> {
+        let images = scan_images(&media_config);
+        if images.is_empty() && media_config.allow_empty {
+            warn!("No supported image found in directories: {}, starting empty",
+                media_config.media.join(", "));
+        } else if images.is_empty() {
+            return Err(format!(
+                "No supported image found in directories: {}",
+                media_config.media.join(", ")));
+        }
+
+        if let Some(cache_dir) = &media_config.image.cache_dir {
+            fs::create_dir_all(cache_dir)
+                .map_err(|e| format!("Failed to create cache_dir {}: {}", cache_dir, e))?;
+        }
+
+        let (watermark, watermark_digest) = match &media_config.image.watermark_path {
+            Some(path) => {
+                let bytes = fs::read(path)
+                    .map_err(|e| format!("Failed to read watermark_path {}: {}", path, e))?;
+                let mark = image::load_from_memory(&bytes)
+                    .map_err(|e| format!("Failed to load watermark_path {}: {}", path, e))?;
+                (Some(mark), Some(blake3::hash(&bytes)))
+            }
+            None => (None, None),
+        };
+
+        gauge!("nas_images_total").set(images.len() as f64);
+        let cache = NonZeroUsize::new(media_config.image.cache_size)
+            .map(|capacity| Mutex::new(ThumbnailCache::new(capacity, media_config.image.cache_max_bytes)));
+        Ok(MediaState{
+            index: RwLock::new(MediaIndex::new(images, media_roots(&media_config))),
+            cache,
+            cache_dir: media_config.image.cache_dir.as_ref().map(PathBuf::from),
+            last_random_index: AtomicUsize::new(usize::MAX),
+            recent_phashes: Mutex::new(VecDeque::new()),
+            slideshow: Mutex::new(SlideshowState::default()),
+            rng: Mutex::new(match media_config.image.seed {
+                Some(seed) => StdRng::seed_from_u64(seed),
+                None => StdRng::from_entropy(),
+            }),
+            favorites: PathSet::load(media_config.favorites.path.clone().map(PathBuf::from))?,
+            blocked: PathSet::load(media_config.blocklist.path.clone().map(PathBuf::from))?,
+            history: Mutex::new(VecDeque::with_capacity(media_config.api.history_size)),
+            rate_limiter: RateLimiter::new(&media_config.rate_limit),
+            pushes: broadcast::channel(PUSH_CHANNEL_CAPACITY).0,
+            decodes: media_config.limits.max_concurrent_decodes.map(Semaphore::new),
+            watermark,
+            watermark_digest,
+            daily: Mutex::new(None),
+            colors: Mutex::new(HashMap::new()),
+            media_config: ArcSwap::from_pointee(media_config),
+        })
+    }
+
+    pub fn media_config(&self) -> Arc<MediaConfig> {
+        self.media_config.load_full()
+    }
+
+    /// Swaps in a re-read config and rescans with it. Settings that went into
+    /// long-lived parts at startup, like the listener or the thumbnail cache,
+    /// keep their old values until a restart, changing them is only reported.
+    pub fn apply_config(&self, media_config: MediaConfig) {
+        let old = self.media_config();
+        for setting in media_config.restart_only_changes(&old) {
+            warn!("Changed {} only takes effect after a restart", setting);
+        }
+        let rescan = media_config.changes_scan(&old);
+        self.media_config.store(Arc::new(media_config));
+        if !rescan {
+            // Thumbnails may still be rendered differently, from size to watermark.
+            if let Some(cache) = &self.cache {
+                cache.lock().unwrap().clear();
+            }
+        } else if let Err(e) = self.reload() {
+            warn!("Rescan with the reloaded config failed: {}", e);
+        }
+    }
+
+    /// Rescans every media directory and swaps in the result in one step.
+    /// An empty result keeps the current collection unless `allow_empty` is set.
+    fn reload(&self) -> Result<usize, ImageError> {
+        let media_config = self.media_config();
+        let images = scan_images(&media_config);
+        if images.is_empty() && !media_config.allow_empty {
+            warn!("Reload found no images, keeping the current collection");
+            return Err(ImageError::Unavailable);
+        }
+
+        let count = images.len();
+        *self.index.write().unwrap() = MediaIndex::new(images, media_roots(&media_config));
+        if let Some(cache) = &self.cache {
+            cache.lock().unwrap().clear();
+        }
+        gauge!("nas_images_total").set(count as f64);
+        info!("Reloaded media directories, {} images", count);
+        Ok(count)
+    }
+
+    pub fn image_count(&self) -> usize {
+        self.index.read().unwrap().images.len()
+    }
+
+    /// Random pick from the whole collection, `None` once it has become empty.
+    pub fn get_random_image(&self) -> Option<(usize, ImageEntry)> {
+        if !self.blocked.is_empty() {
+            return self.random_matching(|_| true);
+        }
+        let index = self.index.read().unwrap();
+        if index.images.is_empty() {
+            return None;
+        }
+        let weighting = self.media_config().image.weighting;
+        let mut rng = self.rng.lock().unwrap();
+        let random_index = self.draw_varied(&index.images, index.images.len(),
+            || index.random_index(weighting, &mut *rng));
+        Some((random_index, index.images[random_index].clone().with_metadata()))
+    }
+
+    /// Random pick among the images whose modification time falls in `range`,
+    /// or `None` when there is no such image.
+    pub fn get_random_image_filtered(
+        &self,
+        range: &ModifiedRange,
+        aspect: Option<Aspect>,
+    ) -> Option<(usize, ImageEntry)> {
+        self.random_matching(|image| {
+            range.contains(image.modified) && aspect.is_none_or(|aspect| aspect.matches(image))
+        })
+    }
+
+    /// Random pick among the favorited images still in the collection.
+    pub fn get_random_favorite(&self) -> Option<(usize, ImageEntry)> {
+        self.random_matching(|image| self.favorites.contains(&image.path))
+    }
+
+    /// Random pick among the images in the top-level folder `name` of any media
+    /// root, failing with `UnknownChannel` when no root has such a folder.
+    fn get_random_in_channel(&self, name: &str) -> Result<(usize, ImageEntry), ImageError> {
+        let (roots, folders) = {
+            let index = self.index.read().unwrap();
+            (index.roots.clone(), index.channel_folders(name))
+        };
+        if folders.is_empty() {
+            return Err(ImageError::UnknownChannel(name.to_string()));
+        }
+        self.random_matching(|image| folders.contains(&top_level_folder(&roots, &image.path)))
+            .ok_or(ImageError::Unavailable)
+    }
+
+    /// Random pick among the unblocked images accepted by `matches`, spread
+    /// according to the configured weighting.
+    fn random_matching(&self, matches: impl Fn(&ImageEntry) -> bool) -> Option<(usize, ImageEntry)> {
+        let index = self.index.read().unwrap();
+        let allowed = |id: &usize| {
+            let image = &index.images[*id];
+            matches(image) && !self.blocked.contains(&image.path)
+        };
+        let mut groups: Vec<Vec<usize>> = match self.media_config().image.weighting {
+            Weighting::Uniform => vec![(0..index.images.len()).filter(allowed).collect()],
+            Weighting::ByFolder => index.folders.values()
+                .map(|folder| folder.iter().copied().filter(allowed).collect())
+                .collect(),
+        };
+        groups.retain(|group| !group.is_empty());
+        let candidate_count: usize = groups.iter().map(Vec::len).sum();
+        if candidate_count == 0 {
+            return None;
+        }
+
+        let mut rng = self.rng.lock().unwrap();
+        let random_index = self.draw_varied(&index.images, candidate_count, || {
+            let group = &groups[rng.gen_range(0..groups.len())];
+            group[rng.gen_range(0..group.len())]
+        });
+        Some((random_index, index.images[random_index].clone().with_metadata()))
+    }
+
+    /// Draws until the pick differs from the previous one and, for up to
+    /// MAX_SIMILAR_REDRAWS draws, does not resemble any recent pick.
+    fn draw_varied(
+        &self,
+        images: &[ImageEntry],
+        candidate_count: usize,
+        mut draw: impl FnMut() -> usize,
+    ) -> usize {
+        let last_index = self.last_random_index.load(Ordering::Relaxed);
+        let mut redraws = 0;
+        let random_index = loop {
+            let random_index = draw();
+            if candidate_count > 1 && random_index == last_index {
+                continue;
+            }
+            if redraws < MAX_SIMILAR_REDRAWS && self.resembles_recent(&images[random_index]) {
+                redraws += 1;
+                continue;
+            }
+            break random_index;
+        };
+        self.last_random_index.store(random_index, Ordering::Relaxed);
+        self.remember_pick(&images[random_index]);
+        random_index
+    }
+
+    /// Whether `image` is within `near_duplicate_distance` bits of a recent pick.
+    fn resembles_recent(&self, image: &ImageEntry) -> bool {
+        let Some(distance) = self.media_config().image.near_duplicate_distance else {
+            return false;
+        };
+        let Some(phash) = image.phash else {
+            return false;
+        };
+        self.recent_phashes.lock().unwrap().iter()
+            .any(|recent| (recent ^ phash).count_ones() <= distance)
+    }
+
+    fn remember_pick(&self, image: &ImageEntry) {
+        let Some(phash) = image.phash else {
+            return;
+        };
+        let mut recent = self.recent_phashes.lock().unwrap();
+        recent.push_front(phash);
+        recent.truncate(self.media_config().image.near_duplicate_window);
+    }
+
+    /// The image of `day`, drawn from a generator seeded with the day alone so
+    /// it only depends on the day and the collection. Once drawn it is kept for
+    /// the rest of the day, even when a rescan changes the collection, unless
+    /// it disappears or gets blocked.
+    pub fn get_daily_image(&self, day: u64) -> Option<(usize, ImageEntry)> {
+        let index = self.index.read().unwrap();
+        let mut daily = self.daily.lock().unwrap();
+        let kept = daily.as_ref()
+            .filter(|(kept_day, path)| *kept_day == day && !self.blocked.contains(path))
+            .and_then(|(_, path)| index.images.iter().position(|image| image.path == *path));
+        let id = match kept {
+            Some(id) => id,
+            None => {
+                let candidates: Vec<usize> = (0..index.images.len())
+                    .filter(|id| !self.blocked.contains(&index.images[*id].path))
+                    .collect();
+                if candidates.is_empty() {
+                    return None;
+                }
+                let id = candidates[StdRng::seed_from_u64(day).gen_range(0..candidates.len())];
+                *daily = Some((day, index.images[id].path.clone()));
+                id
+            }
+        };
+        Some((id, index.images[id].clone().with_metadata()))
+    }
+
+    pub fn get_next_image(&self) -> Option<(usize, ImageEntry)> {
+        let index = self.index.read().unwrap();
+        if index.images.is_empty() {
+            return None;
+        }
+        let mut rng = self.rng.lock().unwrap();
+        let mut slideshow = self.slideshow.lock().unwrap();
+        // Blocked images are stepped over. The rest of the current shuffle plus one
+        // whole new one is enough to reach every unblocked image at least once.
+        (0..2 * index.images.len())
+            .map(|_| slideshow.next_index(index.images.len(), &mut *rng))
+            .find(|id| !self.blocked.contains(&index.images[*id].path))
+            .map(|id| (id, index.images[id].clone().with_metadata()))
+    }
+
+    /// Total image count together with up to `limit` ids and paths from `offset`.
+    pub fn list_images(&self, offset: usize, limit: usize) -> (usize, Vec<(usize, String)>) {
+        let index = self.index.read().unwrap();
+        let page = index.images.iter()
+            .enumerate()
+            .skip(offset)
+            .take(limit)
+            .map(|(id, image)| (id, image.path.clone()))
+            .collect();
+        (index.images.len(), page)
+    }
+
+    /// Up to `limit` ids and paths whose file name contains `query`, ignoring case.
+    pub fn search_images(&self, query: &str, limit: usize) -> Vec<(usize, String)> {
+        let query = query.to_lowercase();
+        self.index.read().unwrap().images.iter()
+            .enumerate()
+            .filter(|(_, image)| Path::new(&image.path).file_name()
+                .and_then(|name| name.to_str())
+                .is_some_and(|name| name.to_lowercase().contains(&query)))
+            .take(limit)
+            .map(|(id, image)| (id, image.path.clone()))
+            .collect()
+    }
+
+    fn record_served(&self, img_path: &str) {
+        let capacity = self.media_config().api.history_size;
+        if capacity == 0 {
+            return;
+        }
+        let mut history = self.history.lock().unwrap();
+        history.truncate(capacity - 1);
+        history.push_front(img_path.to_string());
+    }
+
+    /// Recently served images still in the collection as ids and paths, newest first.
+    /// Paths are kept rather than ids so the history survives rescans.
+    pub fn served_history(&self) -> Vec<(usize, String)> {
+        let history = self.history.lock().unwrap().clone();
+        history.into_iter()
+            .filter_map(|img_path| self.find_id(&img_path).map(|id| (id, img_path)))
+            .collect()
+    }
+
+    /// Decodes up to `scrub.batch_size` images starting at position `cursor`, one
+    /// at a time, and returns where the next batch starts. The batch is cut short
+    /// whenever live requests hold every decode slot, and after each image the
+    /// scrubber rests as long as decoding took so it never hogs a core.
+    /// IO errors, such as an unmounted share, end the batch without blocking
+    /// anything, only corrupt data counts as a failed scrub.
+    fn scrub_batch(&self, cursor: usize) -> usize {
+        let config = &self.media_config().scrub;
+        let (start, batch): (usize, Vec<String>) = {
+            let index = self.index.read().unwrap();
+            if index.images.is_empty() {
+                return 0;
+            }
+            let start = cursor % index.images.len();
+            let batch = index.images.iter()
+                .cycle()
+                .skip(start)
+                .take(config.batch_size.clamp(1, index.images.len()))
+                .map(|image| image.path.clone())
+                .collect();
+            (start, batch)
+        };
+
+        for (scrubbed, img_path) in batch.iter().enumerate() {
+            // Blocked images are out of the rotation, decoding them again is wasted.
+            if self.blocked.contains(img_path) {
+                continue;
+            }
+            let started = Instant::now();
+            let decoded = {
+                let _permit = match &self.decodes {
+                    Some(decodes) => match decodes.try_acquire() {
+                        Ok(permit) => Some(permit),
+                        Err(_) => return start + scrubbed,
+                    },
+                    None => None,
+                };
+                decode_image(img_path, &self.media_config().limits)
+            };
+            // The slot is free again while resting, live requests can take it.
+            std::thread::sleep(started.elapsed());
+            let e = match decoded {
+                Ok(_) => continue,
+                Err(ImageError::IO(e)) => {
+                    warn!("Scrub could not read {}, ending the batch: {}", img_path, e);
+                    counter!("nas_scrub_failures_total", "kind" => "io").increment(1);
+                    return start + scrubbed + 1;
+                }
+                Err(e) => e,
+            };
+            warn!("Scrub failed to decode {}: {}", img_path, e);
+            counter!("nas_scrub_failures_total", "kind" => e.kind()).increment(1);
+            if config.block_failed && e.is_corrupt() {
+                match self.blocked.insert(img_path.clone()) {
+                    Ok(true) => info!("Blocked image {} after a failed scrub", img_path),
+                    Ok(false) => {}
+                    Err(e) => warn!("Failed to block {}: {}", img_path, e),
+                }
+            }
+        }
+        start + batch.len()
+    }
+
+    /// Summary of the in-memory entries, nothing is read from disk.
+    fn stats(&self) -> StatsResponse {
+        let index = self.index.read().unwrap();
+        let mut formats = BTreeMap::new();
+        let mut folders = HashSet::new();
+        let mut total_bytes = 0;
+        let mut unknown_size = 0;
+        for image in &index.images {
+            let path = Path::new(&image.path);
+            let format = format_name(path).unwrap_or_else(|| "unknown".to_string());
+            *formats.entry(format).or_insert(0) += 1;
+            folders.insert(path.parent());
+            match image.size {
+                Some(size) => total_bytes += size,
+                None => unknown_size += 1,
+            }
+        }
+        StatsResponse {
+            total: index.images.len(),
+            formats,
+            total_bytes,
+            unknown_size,
+            folders: folders.len(),
+        }
+    }
+
+    pub fn find_id(&self, img_path: &str) -> Option<usize> {
+        self.index.read().unwrap().images.iter().position(|image| image.path == img_path)
+    }
+
+    pub fn get_image(&self, index: usize) -> Option<ImageEntry> {
+        self.index.read().unwrap().images.get(index).cloned().map(ImageEntry::with_metadata)
+    }
+
+    /// Adds a newly found image, or refreshes the recorded details of a known one.
+    pub fn add_image(&self, img_path: String) {
+        let media_config = self.media_config();
+        let image = ImageEntry::new(img_path).with_dimensions(&media_config.limits);
+        if !media_config.scan_filter.is_large_enough(&image) {
+            debug!("Skipping {}, it is below min_width/min_height", image.path);
+            return;
+        }
+        let image = image.with_phash(&media_config);
+        let mut index = self.index.write().unwrap();
+        match index.images.iter_mut().find(|known| known.path == image.path) {
+            Some(known) => *known = image,
+            None => {
+                info!("Added image {}", image.path);
+                index.push(image);
+                gauge!("nas_images_total").set(index.images.len() as f64);
+            }
+        }
+    }
+
+    pub fn remove_images_under(&self, removed: &Path) {
+        let mut index = self.index.write().unwrap();
+        index.images.retain(|image| {
+            let keep = !Path::new(&image.path).starts_with(removed);
+            if !keep {
+                info!("Removed image {}", image.path);
+            }
+            keep
+        });
+        index.regroup();
+        gauge!("nas_images_total").set(index.images.len() as f64);
+    }
+
+    pub fn invalidate_thumbnails(&self, img_path: &str) {
+        let Some(cache) = &self.cache else {
+            return;
+        };
+        cache.lock().unwrap().remove_path(img_path);
+    }
+
+    /// Where the encoded thumbnail for `img_path` at `spec` is kept on disk,
+    /// or `None` when no `cache_dir` is configured or `spec` is not the default
+    /// thumbnail in some format.
+    fn cached_thumbnail_path(&self, img_path: &str, spec: ThumbnailSpec) -> Option<PathBuf> {
+        let cache_dir = self.cache_dir.as_ref()?;
+        let media_config = self.media_config();
+        // Any other size or quality would let clients fill the disk by varying
+        // the query. The default spec never has a byte budget, whose resulting
+        // quality could not be told from the file either.
+        if spec != media_config.image.default_spec(spec.format) {
+            return None;
+        }
+        let mut hasher = DefaultHasher::new();
+        img_path.hash(&mut hasher);
+        spec.hash(&mut hasher);
+        self.hash_rendering(&mut hasher);
+        let extension = spec.format.image_format().extensions_str()[0];
+        Some(cache_dir.join(format!("{:016x}.{}", hasher.finish(), extension)))
+    }
+
+    /// Deletes files in `cache_dir` that no current image's default thumbnail
+    /// is kept in: those of removed originals, of an earlier default size or
+    /// spec layout, and partial writes. Other files there are left alone.
+    pub fn prune_disk_cache(&self) {
+        let Some(cache_dir) = &self.cache_dir else {
+            return;
+        };
+        let media_config = self.media_config();
+        let expected: HashSet<PathBuf> = self.index.read().unwrap().images.iter()
+            .flat_map(|image| OutputFormat::ALL.into_iter()
+                .filter_map(|format| self.cached_thumbnail_path(
+                    &image.path, media_config.image.default_spec(format))))
+            .collect();
+        let entries = match fs::read_dir(cache_dir) {
+            Ok(entries) => entries,
+            Err(e) => {
+                warn!("Failed to list cache_dir {}: {}", cache_dir.display(), e);
+                return;
+            }
+        };
+
+        let mut removed = 0;
+        for path in entries.flatten().map(|entry| entry.path()) {
+            // Thumbnails and partial writes alike start with the 16 digit hash.
+            let is_thumbnail = path.file_name()
+                .and_then(|name| name.to_str())
+                .and_then(|name| name.split('.').next())
+                .is_some_and(|hash| hash.len() == 16 && hash.chars().all(|c| c.is_ascii_hexdigit()));
+            if !is_thumbnail || expected.contains(&path) {
+                continue;
+            }
+            match fs::remove_file(&path) {
+                Ok(()) => removed += 1,
+                Err(e) => warn!("Failed to remove stale thumbnail {}: {}", path.display(), e),
+            }
+        }
+        if removed > 0 {
+            info!("Removed {} stale thumbnails from {}", removed, cache_dir.display());
+        }
+    }
+
+    /// Feeds what changes every thumbnail's bytes, but is not part of its spec,
+    /// into `hasher`, so cached files and ETags from before a change are not
+    /// served after it. The watermark counts as loaded, not as configured.
+    fn hash_rendering(&self, hasher: &mut impl Hasher) {
+        let config = &self.media_config().image;
+        config.strip_metadata.hash(hasher);
+        config.preserve_icc.hash(hasher);
+        config.watermark_position.hash(hasher);
+        // GIFs may be served untouched instead of as a thumbnail.
+        config.gif_passthrough.hash(hasher);
+        config.gif_max_bytes.hash(hasher);
+        self.watermark_digest.map(|digest| *digest.as_bytes()).hash(hasher);
+    }
+
+    fn thumbnail(&self, image: &ImageEntry, spec: ThumbnailSpec) -> Result<Thumbnail, ImageError> {
+        let Some(cache) = &self.cache else {
+            return self.disk_thumbnail(image, spec);
+        };
+
+        let key = (image.path.clone(), spec);
+        if let Some(thumbnail) = cache.lock().unwrap().get(&key) {
+            return Ok(thumbnail);
+        }
+
+        let thumbnail = self.disk_thumbnail(image, spec)?;
+        cache.lock().unwrap().put(key, thumbnail.clone());
+        Ok(thumbnail)
+    }
+
+    /// A `cols` by `rows` grid of random picks, each cropped to fill its cell.
+    /// The grid is at most MAX_RESOLUTION wide and high, cells whose pick
+    /// could not be decoded in `random_attempts` tries stay black.
+    fn render_mosaic(&self, cols: u32, rows: u32, spec: ThumbnailSpec) -> Result<Thumbnail, ImageError> {
+        let media_config = self.media_config();
+        let cell_width = spec.width.or(spec.height).unwrap_or(MAX_RESOLUTION).min(MAX_RESOLUTION / cols);
+        let cell_height = spec.height.or(spec.width).unwrap_or(MAX_RESOLUTION).min(MAX_RESOLUTION / rows);
+        let filter = spec.filter.filter_type().unwrap_or(FilterType::Triangle);
+        let mut canvas = RgbImage::new(cols * cell_width, rows * cell_height);
+        for cell in 0..cols * rows {
+            for _ in 0..media_config.image.random_attempts.max(1) {
+                let (_, image) = self.get_random_image().ok_or(ImageError::Unavailable)?;
+                let decoded = {
+                    let _permit = self.decode_permit()?;
+                    decode_upright(&image.path, &media_config.limits)
+                };
+                match decoded {
+                    Ok(img) => {
+                        let tile = img.resize_to_fill(cell_width, cell_height, filter).into_rgb8();
+                        let x = i64::from(cell % cols * cell_width);
+                        let y = i64::from(cell / cols * cell_height);
+                        image::imageops::replace(&mut canvas, &tile, x, y);
+                        break;
+                    }
+                    Err(e) => warn!("Skipping unreadable image {} in mosaic: {}", image.path, e),
+                }
+            }
+        }
+
+        let mut mosaic = spec.effect.apply(DynamicImage::ImageRgb8(canvas));
+        if let Some(mark) = &self.watermark {
+            apply_watermark(&mut mosaic, mark, media_config.image.watermark_position);
+        }
+        encode_image(&mosaic, spec, SourceMetadata { exif: None, icc: None })
+            .map_err(ImageError::Encode)
+    }
+
+    /// The average color of `image`, weighted by opacity. Computed on a
+    /// COLOR_SAMPLE_SIDE thumbnail and kept until the file changes.
+    fn average_color(&self, image: &ImageEntry) -> Result<[u8; 3], ImageError> {
+        if let Some((modified, color)) = self.colors.lock().unwrap().get(&image.path)
+            && *modified == image.modified {
+            return Ok(*color);
+        }
+
+        let sample = {
+            let _permit = self.decode_permit()?;
+            decode_image(&image.path, &self.media_config().limits)?
+                .thumbnail(COLOR_SAMPLE_SIDE, COLOR_SAMPLE_SIDE)
+                .into_rgba8()
+        };
+        let mut sums = [0u64; 3];
+        let mut weight = 0u64;
+        for pixel in sample.pixels() {
+            let alpha = u64::from(pixel[3]);
+            for (sum, channel) in sums.iter_mut().zip(pixel.0) {
+                *sum += u64::from(channel) * alpha;
+            }
+            weight += alpha;
+        }
+        // Fully transparent images come out black.
+        let color = sums.map(|sum| (sum + weight / 2).checked_div(weight).unwrap_or(0) as u8);
+        self.colors.lock().unwrap().insert(image.path.clone(), (image.modified, color));
+        Ok(color)
+    }
+
+    /// The response body for `image`: the thumbnail, or the untouched file for
+    /// animated GIFs when `gif_passthrough` is enabled. `X-Source-Format` names
+    /// the original's format as guessed from its extension.
+    fn render(&self, image: &ImageEntry, spec: ThumbnailSpec) -> Result<Response<Body>, ImageError> {
+        let mut response = if self.should_pass_through(image)? {
+            let bytes = self.media_config().limits.retry_io(&image.path, || fs::read(&image.path))
+                .map_err(ImageError::IO)?;
+            image_response(Bytes::from(bytes), "image/gif")
+        } else {
+            let thumbnail = self.thumbnail(image, spec)?;
+            let mut response = image_response(thumbnail.bytes, spec.format.content_type());
+            if let Some(quality) = thumbnail.quality {
+                response.headers_mut().insert(QUALITY_USED_HEADER, HeaderValue::from(u16::from(quality)));
+            }
+            response
+        };
+        if let Some(format) = format_name(Path::new(&image.path))
+            .and_then(|format| HeaderValue::from_str(&format).ok()) {
+            response.headers_mut().insert(SOURCE_FORMAT_HEADER, format);
+        }
+        Ok(response)
+    }
+
+    fn should_pass_through(&self, image: &ImageEntry) -> Result<bool, ImageError> {
+        let config = &self.media_config().image;
+        if !config.gif_passthrough {
+            return Ok(false);
+        }
+        // Oversized GIFs fall back to a still thumbnail of their first frame.
+        if let (Some(max), Some(size)) = (config.gif_max_bytes, image.size)
+            && size > max {
+            return Ok(false);
+        }
+        let mut magic = [0u8; 6];
+        let mut file = self.media_config().limits.retry_io(&image.path, || File::open(&image.path))
+            .map_err(ImageError::IO)?;
+        match file.read_exact(&mut magic) {
+            Ok(()) => Ok(&magic == b"GIF87a" || &magic == b"GIF89a"),
+            Err(e) if e.kind() == io::ErrorKind::UnexpectedEof => Ok(false),
+            Err(e) => Err(ImageError::IO(e)),
+        }
+    }
+
+    /// Waits up to `decode_wait_ms` for a free decode slot, failing with
+    /// `Overloaded` after that. Must be called from a blocking thread.
+    fn decode_permit(&self) -> Result<Option<SemaphorePermit<'_>>, ImageError> {
+        let Some(decodes) = &self.decodes else {
+            return Ok(None);
+        };
+        if let Ok(permit) = decodes.try_acquire() {
+            return Ok(Some(permit));
+        }
+        let wait = Duration::from_millis(self.media_config().limits.decode_wait_ms);
+        match tokio::runtime::Handle::current().block_on(tokio::time::timeout(wait, decodes.acquire())) {
+            Ok(permit) => Ok(Some(permit.expect("decode semaphore is never closed"))),
+            Err(_) => Err(ImageError::Overloaded),
+        }
+    }
+
+    /// Decodes and encodes `img_path` once a decode slot is free.
+    fn encode(&self, img_path: &str, spec: ThumbnailSpec) -> Result<Thumbnail, ImageError> {
+        let _permit = self.decode_permit()?;
+        encode_thumbnail(img_path, spec, &self.media_config(), self.watermark.as_ref())
+    }
+
+    /// Serves the thumbnail from `cache_dir` when it is newer than the original,
+    /// otherwise encodes it and writes it back for the next request.
+    fn disk_thumbnail(&self, image: &ImageEntry, spec: ThumbnailSpec) -> Result<Thumbnail, ImageError> {
+        let img_path = image.path.as_str();
+        let Some(cached_path) = self.cached_thumbnail_path(img_path, spec) else {
+            return self.encode(img_path, spec);
+        };
+
+        let cached_modified = fs::metadata(&cached_path).and_then(|metadata| metadata.modified());
+        if let (Some(original), Ok(cached)) = (image.modified, cached_modified)
+            && cached >= original {
+            match fs::read(&cached_path) {
+                Ok(bytes) => return Ok(Thumbnail { bytes: Bytes::from(bytes), quality: None }),
+                Err(e) => warn!("Failed to read cached thumbnail {}: {}", cached_path.display(), e),
+            }
+        }
+
+        let thumbnail = self.encode(img_path, spec)?;
+        // Write to a name of this writer's own first, so a concurrent reader never
+        // sees a partial file and concurrent writers never interleave.
+        static PARTIAL_WRITES: AtomicUsize = AtomicUsize::new(0);
+        let partial_path = cached_path.with_extension(format!("{}-{}.partial",
+            std::process::id(), PARTIAL_WRITES.fetch_add(1, Ordering::Relaxed)));
+        if let Err(e) = fs::write(&partial_path, &thumbnail.bytes)
+            .and_then(|_| fs::rename(&partial_path, &cached_path)) {
+            warn!("Failed to write cached thumbnail {}: {}", cached_path.display(), e);
+            let _ = fs::remove_file(&partial_path);
+        }
+        Ok(thumbnail)
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum OutputFormat {
+    #[default]
+    #[serde(alias = "jpg")]
+    Jpeg,
+    Png,
+    /// Always lossless, the `image` crate has no lossy WebP encoder. It ignores
+    /// `?quality=` and is usually larger than JPEG for photos.
+    Webp,
+    Avif,
+}
+
+impl OutputFormat {
+    const ALL: [OutputFormat; 4] = [OutputFormat::Jpeg, OutputFormat::Png, OutputFormat::Webp, OutputFormat::Avif];
+    /// Served when the `Accept` header allows, best first. WebP is left out, the
+    /// `image` crate only encodes it losslessly, far larger than JPEG for photos.
+    const NEGOTIATED: [OutputFormat; 1] = [OutputFormat::Avif];
+
+    fn image_format(self) -> ImageFormat {
+        match self {
+            OutputFormat::Jpeg => ImageFormat::Jpeg,
+            OutputFormat::Png => ImageFormat::Png,
+            OutputFormat::Webp => ImageFormat::WebP,
+            OutputFormat::Avif => ImageFormat::Avif,
+        }
+    }
+
+    fn content_type(self) -> &'static str {
+        match self {
+            OutputFormat::Jpeg => "image/jpeg",
+            OutputFormat::Png => "image/png",
+            OutputFormat::Webp => "image/webp",
+            OutputFormat::Avif => "image/avif",
+        }
+    }
+
+    /// Picks the format the client prefers in its `Accept` header. Negotiated
+    /// formats must be named outright, wildcards alone get JPEG. A negotiated
+    /// format wins over JPEG at an equal q, and q=0 refuses it.
+    fn negotiate(headers: &HeaderMap) -> Self {
+        let accepted: Vec<(String, f32)> = headers.get_all(header::ACCEPT)
+            .iter()
+            .filter_map(|value| value.to_str().ok())
+            .flat_map(|value| value.split(','))
+            .map(|media_range| {
+                let mut parts = media_range.split(';');
+                let range = parts.next().unwrap_or_default().trim().to_ascii_lowercase();
+                let q = parts
+                    .find_map(|param| param.trim().strip_prefix("q="))
+                    .and_then(|q| q.trim().parse::<f32>().ok())
+                    .unwrap_or(1.0);
+                (range, q)
+            })
+            .collect();
+        let q_of = |range: &str| accepted.iter()
+            .filter(|(accepted, _)| accepted == range)
+            .map(|(_, q)| *q)
+            .reduce(f32::max);
+
+        // The most specific range naming JPEG decides its weight.
+        let jpeg = q_of(OutputFormat::Jpeg.content_type())
+            .or_else(|| q_of("image/*"))
+            .or_else(|| q_of("*/*"))
+            .unwrap_or(0.0);
+        OutputFormat::NEGOTIATED
+            .into_iter()
+            .filter_map(|format| q_of(format.content_type()).map(|q| (format, q)))
+            .filter(|(_, q)| *q > 0.0 && *q >= jpeg)
+            // Best first, so ties keep the earlier format.
+            .reduce(|best, next| if next.1 > best.1 { next } else { best })
+            .map(|(format, _)| format)
+            .unwrap_or_default()
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Fit {
+    /// Fit the whole image inside the square, preserving aspect ratio.
+    #[default]
+    Contain,
+    /// Fill the whole square, center-cropping whatever overflows.
+    Cover,
+}
+
+/// Color treatment applied to the thumbnail before encoding.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+enum Effect {
+    #[default]
+    None,
+    #[serde(alias = "greyscale")]
+    Grayscale,
+    Sepia,
+}
+
+impl Effect {
+    fn apply(self, img: DynamicImage) -> DynamicImage {
+        match self {
+            Effect::None => img,
+            Effect::Grayscale => img.grayscale(),
+            // Alpha is kept as it is, only the color channels are toned.
+            Effect::Sepia if img.color().has_alpha() => {
+                let mut toned = img.into_rgba8();
+                toned.pixels_mut().for_each(|pixel| sepia(&mut pixel.0[..3]));
+                DynamicImage::ImageRgba8(toned)
+            }
+            Effect::Sepia => {
+                let mut toned = img.into_rgb8();
+                toned.pixels_mut().for_each(|pixel| sepia(&mut pixel.0));
+                DynamicImage::ImageRgb8(toned)
+            }
+        }
+    }
+}
+
+/// The classic sepia matrix, applied to one RGB pixel.
+fn sepia(rgb: &mut [u8]) {
+    let [r, g, b] = [rgb[0], rgb[1], rgb[2]].map(f32::from);
+    let tone = |r_weight: f32, g_weight: f32, b_weight: f32| {
+        (r * r_weight + g * g_weight + b * b_weight).min(255.0) as u8
+    };
+    rgb[0] = tone(0.393, 0.769, 0.189);
+    rgb[1] = tone(0.349, 0.686, 0.168);
+    rgb[2] = tone(0.272, 0.534, 0.131);
+}
+
+/// Blends `mark` into a corner of `thumb`, scaled to the thumbnail's size.
+fn apply_watermark(thumb: &mut DynamicImage, mark: &DynamicImage, position: WatermarkPosition) {
+    let (width, height) = (thumb.width(), thumb.height());
+    let mark = mark.resize(
+        (width / WATERMARK_SCALE).max(1),
+        (height / WATERMARK_SCALE).max(1),
+        FilterType::Triangle);
+    let margin = i64::from(width.min(height) / 40);
+    let right = i64::from(width) - i64::from(mark.width()) - margin;
+    let bottom = i64::from(height) - i64::from(mark.height()) - margin;
+    let (x, y) = match position {
+        WatermarkPosition::TopLeft => (margin, margin),
+        WatermarkPosition::TopRight => (right, margin),
+        WatermarkPosition::BottomLeft => (margin, bottom),
+        WatermarkPosition::BottomRight => (right, bottom),
+    };
+    image::imageops::overlay(thumb, &mark, x, y);
+}
+
+/// Resampling used when downscaling.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq, Hash)]
+#[serde(rename_all = "lowercase")]
+pub enum ResizeFilter {
+    /// The fast thumbnail path, cheap but prone to aliasing on fine detail.
+    #[default]
+    Fast,
+    Nearest,
+    Triangle,
+    #[serde(alias = "lanczos3")]
+    Lanczos,
+}
+
+impl ResizeFilter {
+    /// The resampling filter for `resize`, `None` keeps the fast thumbnail path.
+    fn filter_type(self) -> Option<FilterType> {
+        match self {
+            ResizeFilter::Fast => None,
+            ResizeFilter::Nearest => Some(FilterType::Nearest),
+            ResizeFilter::Triangle => Some(FilterType::Triangle),
+            ResizeFilter::Lanczos => Some(FilterType::Lanczos3),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+struct ThumbnailSpec {
+    /// At least one side is set, a missing one follows the aspect ratio.
+    width: Option<u32>,
+    height: Option<u32>,
+    format: OutputFormat,
+    fit: Fit,
+    /// Used by JPEG and AVIF, PNG and lossless WebP have no quality setting.
+    quality: u8,
+    filter: ResizeFilter,
+    /// Largest JPEG the encode may produce, lowering `quality` to fit.
+    max_bytes: Option<u64>,
+    effect: Effect,
+    /// Progressive rather than baseline JPEG, always off for other formats.
+    progressive: bool,
+}
+
+#[derive(Debug, Deserialize)]
+struct ThumbParams {
+    resolution: Option<u32>,
+    w: Option<u32>,
+    h: Option<u32>,
+    format: Option<OutputFormat>,
+    #[serde(default)]
+    fit: Fit,
+    quality: Option<u32>,
+    filter: Option<ResizeFilter>,
+    max_bytes: Option<u64>,
+    #[serde(default)]
+    effect: Effect,
+    progressive: Option<bool>,
+}
+
+impl ThumbParams {
+    fn spec(&self, config: &ImageConfig, headers: &HeaderMap) -> Result<ThumbnailSpec, ImageError> {
+        for (name, value) in [("resolution", self.resolution), ("w", self.w), ("h", self.h)] {
+            if value == Some(0) {
+                return Err(ImageError::BadRequest(format!("{} must be greater than zero", name)));
+            }
+        }
+        // ?w= and ?h= win over ?resolution=, which wins over the configured size.
+        let (width, height) = match (self.w, self.h, self.resolution) {
+            (None, None, Some(resolution)) => (Some(resolution), Some(resolution)),
+            (None, None, None) => config.default_size(),
+            (width, height, _) => (width, height),
+        };
+        let width = width.map(|width| width.min(MAX_RESOLUTION));
+        let height = height.map(|height| height.min(MAX_RESOLUTION));
+        let quality = self.quality
+            .map(|quality| quality.clamp(1, 100) as u8)
+            .unwrap_or(DEFAULT_JPEG_QUALITY);
+        let format = self.format.unwrap_or_else(|| OutputFormat::negotiate(headers));
+        let filter = self.filter.unwrap_or(config.filter);
+        if self.max_bytes == Some(0) {
+            return Err(ImageError::BadRequest("max_bytes must be greater than zero".to_string()));
+        }
+        // Only JPEG has a quality to trade for size.
+        let max_bytes = self.max_bytes.or(config.max_bytes)
+            .filter(|_| format == OutputFormat::Jpeg);
+        let progressive = self.progressive.unwrap_or(config.progressive)
+            && format == OutputFormat::Jpeg;
+        Ok(ThumbnailSpec {
+            width,
+            height,
+            format,
+            fit: self.fit,
+            quality,
+            filter,
+            max_bytes,
+            effect: self.effect,
+            progressive,
+        })
+    }
+}
+
+/// Bounds on an image's modification time, both ends inclusive.
+#[derive(Debug, Default)]
+pub struct ModifiedRange {
+    since: Option<SystemTime>,
+    until: Option<SystemTime>,
+}
+
+impl ModifiedRange {
+    fn is_unbounded(&self) -> bool {
+        self.since.is_none() && self.until.is_none()
+    }
+
+    /// Images without a known modification time only match an unbounded range.
+    fn contains(&self, modified: Option<SystemTime>) -> bool {
+        if self.is_unbounded() {
+            return true;
+        }
+        modified.is_some_and(|modified| {
+            self.since.is_none_or(|since| modified >= since)
+                && self.until.is_none_or(|until| modified <= until)
+        })
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct ModifiedParams {
+    since: Option<String>,
+    until: Option<String>,
+}
+
+impl ModifiedParams {
+    fn range(&self) -> Result<ModifiedRange, ImageError> {
+        Ok(ModifiedRange {
+            since: self.since.as_deref().map(parse_timestamp).transpose()?,
+            until: self.until.as_deref().map(parse_timestamp).transpose()?,
+        })
+    }
+}
+
+/// Shape of the image as served, for frames that only want one kind.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Aspect {
+    Portrait,
+    Landscape,
+    Square,
+}
+
+impl Aspect {
+    /// Compared in integers, so sides exactly at the tolerance stay square.
+    fn of(width: u32, height: u32) -> Aspect {
+        let longer_than = |long: u32, short: u32| {
+            u64::from(long) * 100 > u64::from(short) * (100 + SQUARE_TOLERANCE_PERCENT)
+        };
+        if longer_than(width, height) {
+            Aspect::Landscape
+        } else if longer_than(height, width) {
+            Aspect::Portrait
+        } else {
+            Aspect::Square
+        }
+    }
+
+    /// Images of unknown size, such as manifest entries, never match.
+    fn matches(self, image: &ImageEntry) -> bool {
+        image.dimensions.is_some_and(|(width, height)| Aspect::of(width, height) == self)
+    }
+}
+
+#[derive(Debug, Deserialize)]
+struct AspectParams {
+    orientation: Option<Aspect>,
+}
+
+/// Accepts unix seconds or an RFC 3339 date-time such as `2024-05-01T00:00:00Z`.
+fn parse_timestamp(value: &str) -> Result<SystemTime, ImageError> {
+    if let Ok(seconds) = value.parse::<u64>() {
+        return UNIX_EPOCH.checked_add(Duration::from_secs(seconds))
+            .ok_or_else(|| ImageError::BadRequest(format!("timestamp '{}' is out of range", value)));
+    }
+    time::OffsetDateTime::parse(value, &time::format_description::well_known::Rfc3339)
+        .map(SystemTime::from)
+        .map_err(|e| ImageError::BadRequest(format!("invalid timestamp '{}': {}", value, e)))
+}
+
+/// How the image travels in the response body.
+#[derive(Clone, Copy, Debug, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+enum BodyEncoding {
+    /// Wrapped in JSON for clients that cannot handle binary responses.
+    Base64,
+}
+
+#[derive(Debug, Deserialize)]
+struct EncodeParams {
+    encode: Option<BodyEncoding>,
+}
+
+#[derive(Serialize)]
+struct EncodedImageResponse {
+    /// `None` for the placeholder image.
+    id: Option<usize>,
+    format: String,
+    data: String,
+}
+
+/// Replaces the image body of `response` with JSON holding it as base64. The
+/// other headers, `X-Image-Id` included, are kept.
+async fn encode_body(response: Response<Body>, encoding: Option<BodyEncoding>) -> Response<Body> {
+    let Some(BodyEncoding::Base64) = encoding else {
+        return response;
+    };
+    let (mut parts, body) = response.into_parts();
+    let bytes = axum::body::to_bytes(body, usize::MAX).await
+        .expect("image bodies are buffered in memory");
+    let id = parts.headers.get(IMAGE_ID_HEADER)
+        .and_then(|id| id.to_str().ok())
+        .and_then(|id| id.parse().ok());
+    let format = parts.headers.get(header::CONTENT_TYPE)
+        .and_then(|content_type| content_type.to_str().ok())
+        .map(|content_type| content_type.trim_start_matches("image/").to_string())
+        .unwrap_or_default();
+    let mut encoded = json_response(parts.status, &EncodedImageResponse {
+        id,
+        format,
+        data: BASE64.encode(bytes),
+    });
+    parts.headers.remove(header::CONTENT_LENGTH);
+    parts.headers.remove(header::CONTENT_TYPE);
+    encoded.headers_mut().extend(parts.headers);
+    encoded
+}
+
+/// The span carries the picked image and the thumbnail settings, `decode_ms`
+/// stays empty when the thumbnail came from a cache.
+#[instrument(skip_all, fields(path = Empty, width = Empty, height = Empty, format = Empty, decode_ms = Empty))]
+async fn get_random_art_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<ThumbParams>,
+    Query(modified): Query<ModifiedParams>,
+    Query(aspect): Query<AspectParams>,
+    Query(encode): Query<EncodeParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    counter!("nas_requests_total", "endpoint" => "get_random_art").increment(1);
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let range = modified.range()?;
+    let span = Span::current();
+    span.record("width", spec.width);
+    span.record("height", spec.height);
+    span.record("format", spec.format.content_type());
+    if state.image_count() == 0 {
+        if let Some(placeholder) = &state.media_config().image.placeholder_image {
+            let placeholder = ImageEntry::new(placeholder.clone());
+            match render_blocking(state.clone(), placeholder, spec).await {
+                Ok(mut response) => {
+                    response.headers_mut()
+                        .insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+                    return Ok(encode_body(response, encode.encode).await);
+                }
+                Err(e) => warn!("Failed to serve placeholder image: {}", e),
+            }
+        }
+        // An empty collection is expected with allow_empty, anywhere else it is an outage.
+        if state.media_config().allow_empty {
+            return Ok(StatusCode::NO_CONTENT.into_response());
+        }
+    }
+
+    // A damaged or vanished file should not surface as an error as long as
+    // another random pick can be served instead.
+    let attempts = state.media_config().image.random_attempts.max(1);
+    let mut last_error = None;
+    for _ in 0..attempts {
+        let (id, image) = if range.is_unbounded() && aspect.orientation.is_none() {
+            state.get_random_image().ok_or(ImageError::Unavailable)?
+        } else {
+            state.get_random_image_filtered(&range, aspect.orientation).ok_or(ImageError::NoMatch)?
+        };
+        let img_path = image.path.clone();
+        span.record("path", img_path.as_str());
+        match render_identified(state.clone(), id, image, spec).await {
+            Ok(response) => return Ok(encode_body(response, encode.encode).await),
+            // Another pick would only wait for a decode slot again.
+            Err(e @ ImageError::Overloaded) => return Err(e),
+            Err(e) => {
+                warn!("Skipping unreadable image {}: {}", img_path, e);
+                counter!("nas_skipped_images_total", "kind" => e.kind()).increment(1);
+                last_error = Some(e);
+            }
+        }
+    }
+    Err(last_error.expect("at least one attempt is made"))
+}
+
+async fn channel_random_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(name): extract::Path<String>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let (id, image) = state.get_random_in_channel(&name)?;
+    render_identified(state, id, image, spec).await
+}
+
+#[derive(Debug, Deserialize)]
+struct MosaicParams {
+    #[serde(default = "default_mosaic_side")]
+    cols: u32,
+    #[serde(default = "default_mosaic_side")]
+    rows: u32,
+}
+
+fn default_mosaic_side() -> u32 {
+    3
+}
+
+/// The size parameters apply to every cell of the grid.
+async fn mosaic_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(mosaic): Query<MosaicParams>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    for (name, side) in [("cols", mosaic.cols), ("rows", mosaic.rows)] {
+        if !(1..=MAX_MOSAIC_SIDE).contains(&side) {
+            return Err(ImageError::BadRequest(
+                format!("{} must be between 1 and {}", name, MAX_MOSAIC_SIDE)));
+        }
+    }
+    let span = Span::current();
+    let thumbnail = tokio::task::spawn_blocking(move || span.in_scope(
+        || state.render_mosaic(mosaic.cols, mosaic.rows, spec)))
+        .await
+        .expect("mosaic task panicked")?;
+    let mut response = image_response(thumbnail.bytes, spec.format.content_type());
+    response.headers_mut().insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    Ok(response)
+}
+
+async fn next_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let (id, image) = state.get_next_image().ok_or(ImageError::Unavailable)?;
+    render_identified(state, id, image, spec).await
+}
+
+/// Days from the unix epoch to `now`, starting at midnight `utc_offset_minutes`
+/// east of UTC.
+fn day_at(now: SystemTime, utc_offset_minutes: i32) -> u64 {
+    let now = now.duration_since(UNIX_EPOCH).unwrap_or_default().as_secs();
+    now.saturating_add_signed(i64::from(utc_offset_minutes) * 60) / (24 * 60 * 60)
+}
+
+async fn daily_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let day = day_at(SystemTime::now(), state.media_config().api.daily_utc_offset_minutes);
+    let (id, image) = state.get_daily_image(day).ok_or(ImageError::Unavailable)?;
+    render_identified(state, id, image, spec).await
+}
+
+async fn get_image_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    let etag = thumbnail_etag(&image, spec, &state);
+    let cache_control = state.media_config().server.image_cache_control();
+
+    if etag_matches(&headers, &etag) {
+        return Ok(
+            Response::builder()
+                .status(StatusCode::NOT_MODIFIED)
+                .header(header::ETAG, etag)
+                .header(header::CACHE_CONTROL, cache_control)
+                .body(Body::empty())
+                .unwrap()
+        );
+    }
+
+    let mut response = render_blocking(state, image, spec).await?;
+    response.headers_mut().insert(header::ETAG, etag);
+    response.headers_mut().insert(header::CACHE_CONTROL, cache_control);
+    Ok(response)
+}
+
+/// Decoding and encoding are CPU bound, so they run on the blocking pool
+/// instead of stalling an async worker. The request's span follows them there.
+async fn render_blocking(
+    state: Arc<MediaState>,
+    image: ImageEntry,
+    spec: ThumbnailSpec,
+) -> Result<Response<Body>, ImageError> {
+    let span = Span::current();
+    tokio::task::spawn_blocking(move || span.in_scope(|| state.render(&image, spec)))
+        .await
+        .expect("render task panicked")
+}
+
+/// Renders an image the server picked itself, telling the client which one it
+/// got through `X-Image-Id` and `X-Image-Path`.
+async fn render_identified(
+    state: Arc<MediaState>,
+    id: usize,
+    image: ImageEntry,
+    spec: ThumbnailSpec,
+) -> Result<Response<Body>, ImageError> {
+    // Only the file name is exposed, the directory layout stays private.
+    let name = Path::new(&image.path).file_name()
+        .and_then(|name| name.to_str())
+        .and_then(|name| HeaderValue::from_str(name).ok());
+    let img_path = image.path.clone();
+    let mut response = render_blocking(state.clone(), image, spec).await?;
+    state.record_served(&img_path);
+    let headers = response.headers_mut();
+    // The next request for the same URL should get a new pick.
+    headers.insert(header::CACHE_CONTROL, HeaderValue::from_static("no-cache"));
+    headers.insert(IMAGE_ID_HEADER, HeaderValue::from(id));
+    if let Some(name) = name {
+        headers.insert(IMAGE_PATH_HEADER, name);
+    }
+    Ok(response)
+}
+
+fn thumbnail_etag(image: &ImageEntry, spec: ThumbnailSpec, state: &MediaState) -> HeaderValue {
+    let mut hasher = DefaultHasher::new();
+    image.path.hash(&mut hasher);
+    image.modified.hash(&mut hasher);
+    spec.hash(&mut hasher);
+    state.hash_rendering(&mut hasher);
+    let etag = format!("\"{:016x}\"", hasher.finish());
+    HeaderValue::from_str(&etag).expect("hex etag is a valid header value")
+}
+
+fn etag_matches(headers: &HeaderMap, etag: &HeaderValue) -> bool {
+    let Some(if_none_match) = headers.get(header::IF_NONE_MATCH)
+        .and_then(|value| value.to_str().ok()) else {
+        return false;
+    };
+    let etag = etag.to_str().unwrap_or_default();
+    if_none_match
+        .split(',')
+        .map(|candidate| candidate.trim())
+        .any(|candidate| {
+            candidate == "*" || candidate.trim_start_matches("W/") == etag
+        })
+}
+
+#[derive(Serialize)]
+struct CountResponse {
+    count: usize,
+}
+
+async fn count_handler(
+    State(state): State<Arc<MediaState>>,
+) -> impl IntoResponse {
+    json_response(StatusCode::OK, &CountResponse { count: state.image_count() })
+}
+
+#[derive(Debug, Deserialize)]
+struct ListParams {
+    #[serde(default)]
+    offset: usize,
+    limit: Option<usize>,
+}
+
+#[derive(Serialize)]
+struct ListEntry {
+    id: usize,
+    name: String,
+    format: Option<String>,
+}
+
+impl ListEntry {
+    /// Name and format come from the path alone, so listing never touches the disk.
+    fn new(id: usize, img_path: &str) -> Self {
+        let path = Path::new(img_path);
+        ListEntry {
+            id,
+            name: path.file_name()
+                .and_then(|name| name.to_str())
+                .unwrap_or_default()
+                .to_string(),
+            format: format_name(path),
+        }
+    }
+}
+
+/// Lowercase format name guessed from the extension, e.g. `"jpeg"`.
+fn format_name(path: &Path) -> Option<String> {
+    if let Ok(format) = ImageFormat::from_path(path) {
+        return Some(format!("{:?}", format).to_lowercase());
+    }
+    // HEIF has no `ImageFormat`, it is only decoded with the heif feature.
+    let extension = path.extension()?.to_str()?.to_lowercase();
+    matches!(extension.as_str(), "heic" | "heif").then(|| "heif".to_string())
+}
+
+#[derive(Serialize)]
+struct ListResponse {
+    total: usize,
+    offset: usize,
+    images: Vec<ListEntry>,
+}
+
+async fn list_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<ListParams>,
+) -> impl IntoResponse {
+    let limit = params.limit.unwrap_or(DEFAULT_LIST_LIMIT).min(MAX_LIST_LIMIT);
+    let (total, page) = state.list_images(params.offset, limit);
+    let images = page.into_iter().map(|(id, img_path)| ListEntry::new(id, &img_path)).collect();
+    json_response(StatusCode::OK, &ListResponse { total, offset: params.offset, images })
+}
+
+#[derive(Serialize)]
+struct StatsResponse {
+    total: usize,
+    /// Image count per format, unrecognised extensions under `"unknown"`.
+    formats: BTreeMap<String, usize>,
+    total_bytes: u64,
+    /// Images whose size was not recorded, left out of `total_bytes`.
+    unknown_size: usize,
+    folders: usize,
+}
+
+async fn stats_handler(
+    State(state): State<Arc<MediaState>>,
+) -> impl IntoResponse {
+    json_response(StatusCode::OK, &state.stats())
+}
+
+#[derive(Serialize)]
+struct HistoryResponse {
+    images: Vec<ListEntry>,
+}
+
+async fn history_handler(
+    State(state): State<Arc<MediaState>>,
+) -> impl IntoResponse {
+    let images = state.served_history().into_iter()
+        .map(|(id, img_path)| ListEntry::new(id, &img_path))
+        .collect();
+    json_response(StatusCode::OK, &HistoryResponse { images })
+}
+
+#[derive(Debug, Deserialize)]
+struct SearchParams {
+    q: String,
+}
+
+#[derive(Serialize)]
+struct SearchResponse {
+    images: Vec<ListEntry>,
+}
+
+async fn search_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<SearchParams>,
+) -> Result<impl IntoResponse, ImageError> {
+    if params.q.is_empty() {
+        return Err(ImageError::BadRequest("q must not be empty".to_string()));
+    }
+    let images = state.search_images(&params.q, state.media_config().api.search_limit)
+        .into_iter()
+        .map(|(id, img_path)| ListEntry::new(id, &img_path))
+        .collect();
+    Ok(json_response(StatusCode::OK, &SearchResponse { images }))
+}
+
+#[derive(Serialize)]
+struct FavoriteResponse {
+    id: usize,
+    favorite: bool,
+}
+
+async fn add_favorite_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    if state.favorites.insert(image.path.clone()).map_err(ImageError::IO)? {
+        info!("Added favorite {}", image.path);
+    }
+    Ok(json_response(StatusCode::OK, &FavoriteResponse { id, favorite: true }))
+}
+
+async fn remove_favorite_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    if state.favorites.remove(&image.path).map_err(ImageError::IO)? {
+        info!("Removed favorite {}", image.path);
+    }
+    Ok(json_response(StatusCode::OK, &FavoriteResponse { id, favorite: false }))
+}
+
+async fn get_random_favorite_handler(
+    State(state): State<Arc<MediaState>>,
+    Query(params): Query<ThumbParams>,
+    headers: HeaderMap,
+) -> Result<impl IntoResponse, ImageError> {
+    let spec = params.spec(&state.media_config().image, &headers)?;
+    let (id, image) = state.get_random_favorite().ok_or(ImageError::NoMatch)?;
+    render_identified(state, id, image, spec).await
+}
+
+#[derive(Serialize)]
+struct BlockResponse {
+    id: usize,
+    blocked: bool,
+}
+
+async fn block_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    if state.blocked.insert(image.path.clone()).map_err(ImageError::IO)? {
+        info!("Blocked image {}", image.path);
+    }
+    Ok(json_response(StatusCode::OK, &BlockResponse { id, blocked: true }))
+}
+
+async fn unblock_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    if state.blocked.remove(&image.path).map_err(ImageError::IO)? {
+        info!("Unblocked image {}", image.path);
+    }
+    Ok(json_response(StatusCode::OK, &BlockResponse { id, blocked: false }))
+}
+
+#[derive(Serialize)]
+struct BlockedEntry {
+    /// `None` once the file has left the collection.
+    id: Option<usize>,
+    path: String,
+}
+
+#[derive(Serialize)]
+struct BlockedResponse {
+    images: Vec<BlockedEntry>,
+}
+
+async fn blocked_handler(
+    State(state): State<Arc<MediaState>>,
+) -> impl IntoResponse {
+    let images = state.blocked.paths().into_iter()
+        .map(|path| BlockedEntry { id: state.find_id(&path), path })
+        .collect();
+    json_response(StatusCode::OK, &BlockedResponse { images })
+}
+
+async fn ws_handler(
+    State(state): State<Arc<MediaState>>,
+    ws: WebSocketUpgrade,
+) -> impl IntoResponse {
+    let pushes = state.pushes.subscribe();
+    ws.on_upgrade(move |socket| push_to_socket(socket, pushes))
+}
+
+/// Forwards every pushed image to one client until either side goes away.
+async fn push_to_socket(mut socket: WebSocket, mut pushes: broadcast::Receiver<String>) {
+    loop {
+        tokio::select! {
+            pushed = pushes.recv() => match pushed {
+                Ok(message) => {
+                    if socket.send(Message::Text(message)).await.is_err() {
+                        break;
+                    }
+                }
+                // A client that fell behind simply continues with the newest image.
+                Err(broadcast::error::RecvError::Lagged(_)) => {}
+                Err(broadcast::error::RecvError::Closed) => break,
+            },
+            // Anything the client sends is ignored, it only matters whether it is still there.
+            received = socket.recv() => match received {
+                Some(Ok(Message::Close(_))) | Some(Err(_)) | None => break,
+                Some(Ok(_)) => {}
+            },
+        }
+    }
+}
+
+async fn reload_handler(
+    State(state): State<Arc<MediaState>>,
+) -> Result<impl IntoResponse, ImageError> {
+    // A rescan walks the whole library, keep it off the async workers.
+    let count = tokio::task::spawn_blocking(move || state.reload())
+        .await
+        .expect("reload task panicked")?;
+    Ok(json_response(StatusCode::OK, &CountResponse { count }))
+}
+
+#[derive(Serialize)]
+struct MetadataResponse {
+    id: usize,
+    name: String,
+    extension: String,
+    format: Option<String>,
+    width: u32,
+    height: u32,
+    size: u64,
+}
+
+#[derive(Serialize)]
+struct ColorResponse {
+    id: usize,
+    r: u8,
+    g: u8,
+    b: u8,
+    /// As a CSS color, e.g. `#1a2b3c`.
+    hex: String,
+}
+
+#[derive(Serialize)]
+struct SrcsetEntry {
+    size: u32,
+    url: String,
+}
+
+#[derive(Serialize)]
+struct SrcsetResponse {
+    id: usize,
+    sources: Vec<SrcsetEntry>,
+    /// Ready to drop into an `<img srcset>` attribute.
+    srcset: String,
+}
+
+/// Parses `sizes=256,512,1024` as it appears in the query, commas possibly
+/// escaped as `%2C` or `%2c`. Each is clamped to MAX_RESOLUTION and they are
+/// returned deduplicated in ascending order.
+fn parse_sizes(sizes: &str) -> Result<Vec<u32>, ImageError> {
+    let sizes = sizes.replace("%2C", ",").replace("%2c", ",");
+    let mut parsed = Vec::new();
+    for size in sizes.split(',').map(str::trim).filter(|size| !size.is_empty()) {
+        match size.parse::<u32>() {
+            Ok(0) | Err(_) => return Err(ImageError::BadRequest(
+                format!("invalid size '{}', expected a positive integer", size))),
+            Ok(size) => parsed.push(size.min(MAX_RESOLUTION)),
+        }
+    }
+    parsed.sort_unstable();
+    parsed.dedup();
+    if parsed.len() > MAX_SRCSET_SIZES {
+        return Err(ImageError::BadRequest(
+            format!("at most {} distinct sizes are allowed", MAX_SRCSET_SIZES)));
+    }
+    Ok(parsed)
+}
+
+/// The average color of an image, for painting a placeholder of the same
+/// tone while its thumbnail loads.
+async fn color_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    let cache_control = state.media_config().server.image_cache_control();
+    let span = Span::current();
+    let [r, g, b] = tokio::task::spawn_blocking(move || span.in_scope(|| state.average_color(&image)))
+        .await
+        .expect("color task panicked")?;
+    let hex = format!("#{:02x}{:02x}{:02x}", r, g, b);
+    let mut response = json_response(StatusCode::OK, &ColorResponse { id, r, g, b, hex });
+    response.headers_mut().insert(header::CACHE_CONTROL, cache_control);
+    Ok(response)
+}
+
+/// Lists /get_image URLs for each requested size. The remaining query
+/// parameters (format, fit, quality, filter) are carried over to every URL.
+async fn srcset_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+    RawQuery(query): RawQuery,
+) -> Result<impl IntoResponse, ImageError> {
+    if state.get_image(id).is_none() {
+        return Err(ImageError::NotFound(id));
+    }
+
+    let mut sizes = None;
+    let mut forwarded = Vec::new();
+    for pair in query.as_deref().unwrap_or("").split('&').filter(|pair| !pair.is_empty()) {
+        let (key, value) = pair.split_once('=').unwrap_or((pair, ""));
+        match key {
+            "sizes" => sizes = Some(value),
+            "resolution" | "w" | "h" => {}
+            _ => forwarded.push(pair),
+        }
+    }
+    let sizes = match sizes {
+        Some(sizes) => parse_sizes(sizes)?,
+        None => {
+            let (width, height) = state.media_config().image.default_size();
+            vec![width.max(height).unwrap_or(MAX_RESOLUTION)]
+        }
+    };
+    if sizes.is_empty() {
+        return Err(ImageError::BadRequest("sizes must list at least one size".to_string()));
+    }
+
+    let sources: Vec<SrcsetEntry> = sizes.into_iter()
+        .map(|size| {
+            let mut url = format!("/get_image/{}?resolution={}", id, size);
+            for pair in &forwarded {
+                url.push('&');
+                url.push_str(pair);
+            }
+            SrcsetEntry { size, url }
+        })
+        .collect();
+    let srcset = sources.iter()
+        .map(|source| format!("{} {}w", source.url, source.size))
+        .collect::<Vec<_>>()
+        .join(", ");
+    Ok(json_response(StatusCode::OK, &SrcsetResponse { id, sources, srcset }))
+}
+
+async fn metadata_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+) -> Result<impl IntoResponse, ImageError> {
+    let image = state.get_image(id).ok_or(ImageError::NotFound(id))?;
+    // Opening the file and parsing its header is blocking IO.
+    let metadata = tokio::task::spawn_blocking(move || read_metadata(id, &image, &state.media_config().limits))
+        .await
+        .expect("metadata task panicked")?;
+    Ok(json_response(StatusCode::OK, &metadata))
+}
+
+fn read_metadata(id: usize, image: &ImageEntry, limits: &LimitsConfig) -> Result<MetadataResponse, ImageError> {
+    let path = Path::new(&image.path);
+    let size = match image.size {
+        Some(size) => size,
+        None => fs::metadata(path).map_err(ImageError::IO)?.len(),
+    };
+
+    let (format, (width, height)) = limits.retry_decode(&image.path, || {
+        let reader = open_image(&image.path)?;
+        let format = reader.format().map(|format| format!("{:?}", format).to_lowercase());
+        // Only the header is parsed, the pixel data is never decoded.
+        let mut decoder = reader.into_decoder().map_err(decode_error)?;
+        Ok((format, served_dimensions(&mut decoder)))
+    })?;
+
+    let name = path.file_name()
+        .and_then(|name| name.to_str())
+        .unwrap_or_default()
+        .to_string();
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    Ok(MetadataResponse {
+        id,
+        name,
+        extension,
+        format,
+        width,
+        height,
+        size,
+    })
+}
+
+/// Width and height of the image at `img_path` as served, parsing only its header.
+fn image_dimensions(img_path: &str, limits: &LimitsConfig) -> Result<(u32, u32), ImageError> {
+    limits.retry_decode(img_path, || {
+        let mut decoder = open_image(img_path)?.into_decoder().map_err(decode_error)?;
+        Ok(served_dimensions(&mut decoder))
+    })
+}
+
+/// The decoder's dimensions after applying EXIF rotation.
+fn served_dimensions(decoder: &mut impl ImageDecoder) -> (u32, u32) {
+    let (width, height) = decoder.dimensions();
+    match decoder.orientation().unwrap_or(Orientation::NoTransforms) {
+        Orientation::Rotate90 | Orientation::Rotate270
+            | Orientation::Rotate90FlipH | Orientation::Rotate270FlipH => (height, width),
+        _ => (width, height),
+    }
+}
+
+fn content_type_for_path(path: &Path) -> &'static str {
+    let extension = path.extension()
+        .and_then(|extension| extension.to_str())
+        .unwrap_or_default()
+        .to_lowercase();
+    match extension.as_str() {
+        "jpg" | "jpeg" => "image/jpeg",
+        "png" => "image/png",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "bmp" => "image/bmp",
+        "tif" | "tiff" => "image/tiff",
+        "heic" => "image/heic",
+        "heif" => "image/heif",
+        _ => "application/octet-stream",
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum ByteRange {
+    Full,
+    /// Inclusive start and end offsets.
+    Partial(u64, u64),
+    Unsatisfiable,
+}
+
+/// Parses a single `bytes=` range header against a file of `size` bytes.
+fn parse_byte_range(range: &str, size: u64) -> ByteRange {
+    let Some(spec) = range.trim().strip_prefix("bytes=") else {
+        return ByteRange::Unsatisfiable;
+    };
+    // Multipart responses are not supported, serving the full body is allowed.
+    if spec.contains(',') {
+        return ByteRange::Full;
+    }
+    let Some((start, end)) = spec.split_once('-') else {
+        return ByteRange::Unsatisfiable;
+    };
+
+    let last = size.saturating_sub(1);
+    let bounds = match (start.trim(), end.trim()) {
+        ("", "") => None,
+        ("", suffix) => suffix.parse::<u64>().ok()
+            .filter(|suffix| *suffix > 0)
+            .map(|suffix| (size.saturating_sub(suffix), last)),
+        (start, "") => start.parse::<u64>().ok().map(|start| (start, last)),
+        (start, end) => start.parse::<u64>().ok()
+            .zip(end.parse::<u64>().ok())
+            .map(|(start, end)| (start, end.min(last))),
+    };
+
+    match bounds {
+        Some((start, end)) if start < size && start <= end => ByteRange::Partial(start, end),
+        _ => ByteRange::Unsatisfiable,
+    }
+}
+
+async fn original_handler(
+    State(state): State<Arc<MediaState>>,
+    extract::Path(id): extract::Path<usize>,
+    headers: HeaderMap,
+) -> Result<AxumResponse, ImageError> {
+    let img_path = state.get_image(id).ok_or(ImageError::NotFound(id))?.path;
+    let mut file = tokio::fs::File::open(&img_path).await.map_err(ImageError::IO)?;
+    let size = file.metadata().await.map_err(ImageError::IO)?.len();
+    let content_type = content_type_for_path(Path::new(&img_path));
+
+    let range = match headers.get(header::RANGE) {
+        None => ByteRange::Full,
+        Some(value) => value.to_str()
+            .map(|range| parse_byte_range(range, size))
+            .unwrap_or(ByteRange::Unsatisfiable),
+    };
+
+    // Stream from disk so very large originals are never fully held in memory.
+    let response = match range {
+        ByteRange::Full => Response::builder()
+            .status(StatusCode::OK)
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::from_stream(ReaderStream::new(file))),
+        ByteRange::Unsatisfiable => Response::builder()
+            .status(StatusCode::RANGE_NOT_SATISFIABLE)
+            .header(header::CONTENT_RANGE, format!("bytes */{}", size))
+            .body(Body::empty()),
+        ByteRange::Partial(start, end) => {
+            file.seek(io::SeekFrom::Start(start)).await.map_err(ImageError::IO)?;
+            let length = end - start + 1;
+            Response::builder()
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{}", start, end, size))
+                .header(header::CONTENT_LENGTH, length)
+                .body(Body::from_stream(ReaderStream::new(file.take(length))))
+        }
+    };
+
+    let mut response = response.unwrap();
+    response.headers_mut().insert(header::ACCEPT_RANGES, HeaderValue::from_static("bytes"));
+    response.headers_mut().insert(header::CACHE_CONTROL, state.media_config().server.image_cache_control());
+    if response.status() != StatusCode::RANGE_NOT_SATISFIABLE {
+        response.headers_mut().insert(header::CONTENT_TYPE, HeaderValue::from_static(content_type));
+    }
+    Ok(response)
+}
+
+async fn healthz_handler() -> impl IntoResponse {
+    (StatusCode::OK, "ok")
+}
+
+#[derive(Serialize)]
+struct VersionResponse {
+    version: &'static str,
+    commit: &'static str,
+    /// RFC 3339, empty if the build script's timestamp is out of range.
+    built_at: String,
+}
+
+/// Set by build.rs, the commit is "unknown" when built outside a git checkout.
+async fn version_handler() -> impl IntoResponse {
+    let built_at = env!("NAS_BUILD_TIMESTAMP").parse::<i64>().ok()
+        .and_then(|timestamp| time::OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .and_then(|built_at| built_at.format(&time::format_description::well_known::Rfc3339).ok())
+        .unwrap_or_default();
+    json_response(StatusCode::OK, &VersionResponse {
+        version: env!("CARGO_PKG_VERSION"),
+        commit: env!("NAS_GIT_COMMIT"),
+        built_at,
+    })
+}
+
+async fn readyz_handler(
+    State(state): State<Arc<MediaState>>,
+) -> impl IntoResponse {
+    if state.image_count() > 0 {
+        (StatusCode::OK, "ready")
+    } else {
+        (StatusCode::SERVICE_UNAVAILABLE, "no images available")
+    }
+}
+
+fn encode_thumbnail(
+    img_path: &str,
+    spec: ThumbnailSpec,
+    media_config: &MediaConfig,
+    watermark: Option<&DynamicImage>) -> Result<Thumbnail, ImageError> {
+    let limits = &media_config.limits;
+    let decode_started = Instant::now();
+    let (img, metadata) = limits.retry_decode(img_path, || {
+        let mut reader = open_image(img_path)?;
+        reader.limits(limits.image_limits());
+        let mut decoder = reader.into_decoder().map_err(decode_error)?;
+        limits.check_decoded_size(&decoder).map_err(ImageError::TooLarge)?;
+        let metadata = SourceMetadata::read(
+            &mut decoder,
+            !media_config.image.strip_metadata,
+            !media_config.image.strip_metadata || media_config.image.preserve_icc);
+        // A broken EXIF block should not prevent the image from being served.
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+        img.apply_orientation(orientation);
+        Ok((img, metadata))
+    })?;
+    Span::current().record("decode_ms", decode_started.elapsed().as_millis() as u64);
+
+    let started = Instant::now();
+    let width = spec.width.unwrap_or(MAX_RESOLUTION);
+    let height = spec.height.unwrap_or(MAX_RESOLUTION);
+    // With a single side there is nothing to crop against.
+    let fit = if spec.width.is_some() && spec.height.is_some() { spec.fit } else { Fit::Contain };
+    let thumb = match (fit, spec.filter.filter_type()) {
+        (Fit::Contain, None) => img.thumbnail(width, height),
+        (Fit::Contain, Some(filter)) => img.resize(width, height, filter),
+        (Fit::Cover, filter) => img.resize_to_fill(
+            width,
+            height,
+            filter.unwrap_or(FilterType::Triangle)),
+    };
+    let mut thumb = spec.effect.apply(thumb);
+    if let Some(mark) = watermark {
+        apply_watermark(&mut thumb, mark, media_config.image.watermark_position);
+    }
+    let thumbnail = encode_image(&thumb, spec, metadata).map_err(ImageError::Encode)?;
+    histogram!("nas_encode_duration_seconds").record(started.elapsed().as_secs_f64());
+    Ok(thumbnail)
+}
+
+/// Hands `img` to the encoder for `spec.format`, set up with the options of
+/// `spec`. The quality is only reported for JPEGs encoded under a byte budget.
+fn encode_image(
+    img: &DynamicImage,
+    spec: ThumbnailSpec,
+    metadata: SourceMetadata) -> Result<Thumbnail, image::ImageError> {
+    let mut buffer = Cursor::new(Vec::new());
+    match spec.format {
+        OutputFormat::Jpeg => {
+            let (bytes, used) = encode_jpeg(img, &metadata, spec.quality, spec.max_bytes, spec.progressive)?;
+            return Ok(Thumbnail { bytes: Bytes::from(bytes), quality: spec.max_bytes.map(|_| used) });
+        }
+        OutputFormat::Png => img.write_with_encoder(metadata.embed(
+            PngEncoder::new(&mut buffer)))?,
+        OutputFormat::Webp => img.write_with_encoder(metadata.embed(
+            WebPEncoder::new_lossless(&mut buffer)))?,
+        // The AVIF encoder has no way to embed metadata.
+        OutputFormat::Avif => img.write_with_encoder(
+            AvifEncoder::new_with_speed_quality(&mut buffer, AVIF_SPEED, spec.quality))?,
+    }
+    Ok(Thumbnail { bytes: Bytes::from(buffer.into_inner()), quality: None })
+}
+
+/// Encodes at `quality`, then keeps stepping it down while the result is over
+/// `max_bytes`, stopping at MIN_BUDGET_QUALITY. The last attempt is returned
+/// along with its quality, even when it is still too large.
+fn encode_jpeg(
+    img: &DynamicImage,
+    metadata: &SourceMetadata,
+    mut quality: u8,
+    max_bytes: Option<u64>,
+    progressive: bool) -> Result<(Vec<u8>, u8), image::ImageError> {
+    loop {
+        let mut buffer = Vec::new();
+        if progressive {
+            encode_progressive_jpeg(img, metadata, quality, &mut buffer)?;
+        } else {
+            img.write_with_encoder(metadata.clone().embed(
+                JpegEncoder::new_with_quality(&mut buffer, quality)))?;
+        }
+        let fits = max_bytes.is_none_or(|max_bytes| buffer.len() as u64 <= max_bytes);
+        if fits || quality <= MIN_BUDGET_QUALITY {
+            return Ok((buffer, quality));
+        }
+        quality = quality.saturating_sub(BUDGET_QUALITY_STEP).max(MIN_BUDGET_QUALITY);
+    }
+}
+
+/// The `image` crate only writes baseline JPEGs, progressive ones come from
+/// `jpeg-encoder`. Alpha is dropped like it is for baseline output.
+fn encode_progressive_jpeg(
+    img: &DynamicImage,
+    metadata: &SourceMetadata,
+    quality: u8,
+    buffer: &mut Vec<u8>) -> Result<(), image::ImageError> {
+    let encoding_error = |e: jpeg_encoder::EncodingError| image::ImageError::Encoding(
+        image::error::EncodingError::new(ImageFormat::Jpeg.into(), e));
+    let mut encoder = jpeg_encoder::Encoder::new(buffer, quality);
+    encoder.set_progressive(true);
+    if let Some(exif) = &metadata.exif {
+        let segment = [b"Exif\0\0".as_slice(), exif].concat();
+        if let Err(e) = encoder.add_app_segment(1, &segment) {
+            debug!("Dropping EXIF metadata: {}", e);
+        }
+    }
+    if let Some(icc) = &metadata.icc
+        && let Err(e) = encoder.add_icc_profile(icc) {
+        debug!("Dropping ICC profile: {}", e);
+    }
+    // Thumbnails are at most MAX_RESOLUTION wide, well within the u16 the encoder takes.
+    let (width, height) = (img.width() as u16, img.height() as u16);
+    match img {
+        DynamicImage::ImageLuma8(luma) => encoder
+            .encode(luma.as_raw(), width, height, jpeg_encoder::ColorType::Luma),
+        img => encoder
+            .encode(img.to_rgb8().as_raw(), width, height, jpeg_encoder::ColorType::Rgb),
+    }.map_err(encoding_error)
+}
+
+/// EXIF and ICC blocks carried from the source into the thumbnail, according
+/// to `strip_metadata` and `preserve_icc`. JPEG, PNG and WebP output can hold
+/// both, AVIF neither.
+#[derive(Clone)]
+struct SourceMetadata {
+    exif: Option<Vec<u8>>,
+    icc: Option<Vec<u8>>,
+}
+
+impl SourceMetadata {
+    /// Unreadable blocks are dropped rather than failing the request.
+    fn read(decoder: &mut impl ImageDecoder, keep_exif: bool, keep_icc: bool) -> Self {
+        let exif = keep_exif
+            .then(|| decoder.exif_metadata().ok().flatten())
+            .flatten()
+            .map(|mut exif| {
+                // The orientation is applied to the pixels, viewers must not rotate again.
+                let _ = Orientation::remove_from_exif_chunk(&mut exif);
+                exif
+            });
+        let icc = keep_icc.then(|| decoder.icc_profile().ok().flatten()).flatten();
+        SourceMetadata { exif, icc }
+    }
+
+    fn embed<E: ImageEncoder>(self, mut encoder: E) -> E {
+        if let Some(exif) = self.exif
+            && let Err(e) = encoder.set_exif_metadata(exif) {
+            debug!("Dropping EXIF metadata: {}", e);
+        }
+        if let Some(icc) = self.icc
+            && let Err(e) = encoder.set_icc_profile(icc) {
+            debug!("Dropping ICC profile: {}", e);
+        }
+        encoder
+    }
+}
+
+/// Decodes the whole image at `img_path` within `limits`, ignoring its orientation.
+fn decode_image(img_path: &str, limits: &LimitsConfig) -> Result<DynamicImage, ImageError> {
+    limits.retry_decode(img_path, || decode_reader(open_image(img_path)?, limits))
+}
+
+fn decode_reader<R: io::BufRead + io::Seek>(
+    mut reader: ImageReader<R>,
+    limits: &LimitsConfig) -> Result<DynamicImage, ImageError> {
+    reader.limits(limits.image_limits());
+    let decoder = reader.into_decoder().map_err(decode_error)?;
+    limits.check_decoded_size(&decoder).map_err(ImageError::TooLarge)?;
+    DynamicImage::from_decoder(decoder).map_err(decode_error)
+}
+
+/// Opens `img_path` and sniffs its format. Callers wrap the whole decode in
+/// `retry_decode`, most of the file is only read while decoding.
+fn open_image(img_path: &str) -> Result<ImageReader<BufReader<File>>, ImageError> {
+    ImageReader::open(Path::new(img_path))
+        .and_then(|reader| reader.with_guessed_format())
+        .map_err(ImageError::IO)
+}
+
+/// Like `decode_image`, but turned upright according to the EXIF orientation.
+fn decode_upright(img_path: &str, limits: &LimitsConfig) -> Result<DynamicImage, ImageError> {
+    limits.retry_decode(img_path, || {
+        let mut reader = open_image(img_path)?;
+        reader.limits(limits.image_limits());
+        let mut decoder = reader.into_decoder().map_err(decode_error)?;
+        limits.check_decoded_size(&decoder).map_err(ImageError::TooLarge)?;
+        let orientation = decoder.orientation().unwrap_or(Orientation::NoTransforms);
+        let mut img = DynamicImage::from_decoder(decoder).map_err(decode_error)?;
+        img.apply_orientation(orientation);
+        Ok(img)
+    })
+}
+
+/// Keeps limit violations and failed reads apart from files that are simply
+/// broken. A file ending early is truncated, not a read that went wrong.
+fn decode_error(e: image::ImageError) -> ImageError {
+    match e {
+        image::ImageError::Limits(_) => ImageError::TooLarge(e),
+        image::ImageError::IoError(e) if e.kind() != io::ErrorKind::UnexpectedEof => ImageError::IO(e),
+        e => ImageError::Load(e),
+    }
+}
+
+/// Errors a flaky network mount may recover from, anything else such as a
+/// missing file is final.
+fn is_transient(e: &io::Error) -> bool {
+    matches!(e.kind(),
+        io::ErrorKind::WouldBlock
+        | io::ErrorKind::TimedOut
+        | io::ErrorKind::Interrupted
+        | io::ErrorKind::ResourceBusy
+        | io::ErrorKind::StaleNetworkFileHandle)
+}
+
+fn image_response(bytes: Bytes, content_type: &'static str) -> Response<Body> {
+    Response::builder()
+        .status(StatusCode::OK)
+        .header(header::CONTENT_TYPE, content_type)
+        .header(header::VARY, "accept")
+        .body(Body::from(bytes))
+        .unwrap()
+}
+
+#[derive(Debug, Deserialize)]
+pub struct NetworkConfigRaw {
+    pub addr: [u8; 4], 
+    pub port: u16,
+    pub tls_cert: Option<String>,
+    pub tls_key: Option<String>,
+    /// Unix domain socket to listen on instead of `addr` and `port`.
+    pub listen_uds: Option<String>,
+}
+
+#[derive(Clone, Debug)]
+pub struct TlsConfig {
+    pub cert: String,
+    pub key: String,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ImageConfig {
+    /// Square thumbnail size, shorthand for equal `width` and `height`.
+    pub resolution: Option<u32>,
+    /// Thumbnail box, either side may be left out to keep the aspect ratio.
+    /// Takes precedence over `resolution` when set.
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    /// Number of encoded thumbnails kept in memory, 0 disables the cache.
+    #[serde(default = "default_cache_size")]
+    pub cache_size: usize,
+    /// Total bytes the in-memory thumbnails may take up.
+    #[serde(default = "default_cache_max_bytes")]
+    pub cache_max_bytes: usize,
+    /// Directory where encoded thumbnails are persisted across restarts. Only
+    /// the default size is kept, stale files are removed at startup.
+    pub cache_dir: Option<String>,
+    /// File extensions picked up by the scan, compared case-insensitively.
+    #[serde(default = "default_extensions")]
+    pub extensions: Vec<String>,
+    /// How many random images are tried before /get_random_art gives up.
+    #[serde(default = "default_random_attempts")]
+    pub random_attempts: usize,
+    /// Serve GIFs as the original file so animations keep playing.
+    #[serde(default)]
+    pub gif_passthrough: bool,
+    /// GIFs larger than this are thumbnailed like any other image instead.
+    pub gif_max_bytes: Option<u64>,
+    /// Served by /get_random_art, thumbnailed as usual, while the collection is
+    /// empty. Pair with `scan.allow_empty` to start before the first image syncs.
+    pub placeholder_image: Option<String>,
+    /// Drop EXIF (including GPS) and ICC data from thumbnails. When off, JPEG, PNG
+    /// and WebP thumbnails keep the source's metadata, AVIF never carries any.
+    #[serde(default = "default_strip_metadata")]
+    pub strip_metadata: bool,
+    /// Keep the source's ICC color profile even when `strip_metadata` is on, so
+    /// wide-gamut images such as Adobe RGB keep their colors.
+    #[serde(default)]
+    pub preserve_icc: bool,
+    /// Byte budget for JPEG thumbnails unless a request passes its own `max_bytes`.
+    pub max_bytes: Option<u64>,
+    /// Resampling used for thumbnails unless a request asks for another one.
+    #[serde(default)]
+    pub filter: ResizeFilter,
+    /// Encode JPEG thumbnails progressively unless a request passes `progressive`.
+    #[serde(default)]
+    pub progressive: bool,
+    /// Logo blended into every thumbnail, transparency is kept.
+    pub watermark_path: Option<String>,
+    #[serde(default)]
+    pub watermark_position: WatermarkPosition,
+    /// Random picks whose perceptual hashes differ from one of the last
+    /// `near_duplicate_window` picks in at most this many of 64 bits are redrawn,
+    /// so burst shots do not follow each other. Unset skips hashing entirely.
+    pub near_duplicate_distance: Option<u32>,
+    #[serde(default = "default_near_duplicate_window")]
+    pub near_duplicate_window: usize,
+    /// How /get_random_art spreads its picks across the collection.
+    #[serde(default)]
+    pub weighting: Weighting,
+    /// Fixed seed for random picks and slideshow shuffles, so a sequence can be replayed.
+    pub seed: Option<u64>,
+    /// Pre-render every thumbnail into `cache_dir` in the background at startup.
+    #[serde(default)]
+    pub warm_cache: bool,
+    /// How many thumbnails the warming task encodes at once.
+    #[serde(default = "default_warm_concurrency")]
+    pub warm_concurrency: usize,
+}
+
+impl ImageConfig {
+    /// The thumbnail box used when a request names no size.
+    pub fn default_size(&self) -> (Option<u32>, Option<u32>) {
+        match (self.width, self.height) {
+            (None, None) => (self.resolution, self.resolution),
+            size => size,
+        }
+    }
+
+    /// The spec of a request naming nothing but maybe its format. Only these
+    /// thumbnails are written to `cache_dir`.
+    fn default_spec(&self, format: OutputFormat) -> ThumbnailSpec {
+        let (width, height) = self.default_size();
+        ThumbnailSpec {
+            width,
+            height,
+            format,
+            fit: Fit::default(),
+            quality: DEFAULT_JPEG_QUALITY,
+            filter: self.filter,
+            max_bytes: None,
+            effect: Effect::default(),
+            progressive: self.progressive && format == OutputFormat::Jpeg,
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize, Hash)]
+#[serde(rename_all = "snake_case")]
+pub enum WatermarkPosition {
+    TopLeft,
+    TopRight,
+    BottomLeft,
+    #[default]
+    BottomRight,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ImageSource {
+    /// Walk the media directories.
+    #[default]
+    Scan,
+    /// Read the path list from `manifest_path`.
+    Manifest,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum IndexOrder {
+    /// Sorted by canonical path, so ids are stable across restarts.
+    #[default]
+    Path,
+    /// Whatever order the parallel scan produced.
+    Scan,
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Weighting {
+    /// Every image is equally likely.
+    #[default]
+    Uniform,
+    /// Every top-level folder is equally likely, then every image within it.
+    ByFolder,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScanConfig {
+    /// When non-empty, only paths matching one of these globs are served.
+    #[serde(default)]
+    pub include: Vec<String>,
+    #[serde(default)]
+    pub exclude: Vec<String>,
+    /// Descend into symlinked directories, each real directory is visited once.
+    #[serde(default)]
+    pub follow_symlinks: bool,
+    /// How many directory levels are scanned, 1 takes only the files directly in a
+    /// media directory. Unset means no limit.
+    pub max_depth: Option<usize>,
+    /// Images narrower or lower than this, such as icons, are left out of
+    /// scans. Manifest entries are never checked.
+    pub min_width: Option<u32>,
+    pub min_height: Option<u32>,
+    /// Directories left out of scans by name, on top of every hidden file and
+    /// directory.
+    #[serde(default = "default_ignore_dirs")]
+    pub ignore_dirs: Vec<String>,
+    /// Hash every file at startup and serve only one copy of identical images.
+    #[serde(default)]
+    pub dedupe: bool,
+    /// Start with an empty collection instead of failing, images can arrive later.
+    #[serde(default)]
+    pub allow_empty: bool,
+    #[serde(default)]
+    pub source: ImageSource,
+    /// Newline-delimited image paths, used when `source = "manifest"`.
+    pub manifest_path: Option<String>,
+    /// How scanned images are numbered, manifests always keep their own order.
+    #[serde(default)]
+    pub index_order: IndexOrder,
+}
+
+impl Default for ScanConfig {
+    fn default() -> Self {
+        ScanConfig {
+            include: Vec::new(),
+            exclude: Vec::new(),
+            follow_symlinks: false,
+            max_depth: None,
+            min_width: None,
+            min_height: None,
+            ignore_dirs: default_ignore_dirs(),
+            dedupe: false,
+            allow_empty: false,
+            source: ImageSource::default(),
+            manifest_path: None,
+            index_order: IndexOrder::default(),
+        }
+    }
+}
+
+fn default_ignore_dirs() -> Vec<String> {
+    vec!["@eaDir".to_string(), ".thumbnails".to_string()]
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct AuthConfig {
+    /// When set, image routes require a matching `X-API-Key` header.
+    pub api_key: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct CorsConfig {
+    /// Origins allowed to make cross-origin requests, `"*"` allows any.
+    /// Left empty, no CORS headers are sent and browsers enforce same-origin.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ApiConfig {
+    /// Most results a single /search returns.
+    #[serde(default = "default_search_limit")]
+    pub search_limit: usize,
+    /// How many served picks /history remembers, 0 disables it.
+    #[serde(default = "default_history_size")]
+    pub history_size: usize,
+    /// Where /daily's day starts, in minutes east of UTC. Fixed, so a daylight
+    /// saving change moves the switch by an hour.
+    #[serde(default)]
+    pub daily_utc_offset_minutes: i32,
+}
+
+impl Default for ApiConfig {
+    fn default() -> Self {
+        ApiConfig {
+            search_limit: default_search_limit(),
+            history_size: default_history_size(),
+            daily_utc_offset_minutes: 0,
+        }
+    }
+}
+
+fn default_search_limit() -> usize {
+    50
+}
+
+fn default_history_size() -> usize {
+    50
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct FavoritesConfig {
+    /// JSON file the favorites are kept in, without it they last until restart.
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct BlocklistConfig {
+    /// JSON file blocked paths are kept in, without it they last until restart.
+    pub path: Option<String>,
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct PushConfig {
+    /// Seconds between images pushed to /ws clients, 0 disables the endpoint.
+    #[serde(default)]
+    pub interval_secs: u64,
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ScrubConfig {
+    /// Seconds between scrub batches, 0 disables the scrubber.
+    #[serde(default)]
+    pub interval_secs: u64,
+    /// Images re-decoded per batch.
+    #[serde(default = "default_scrub_batch_size")]
+    pub batch_size: usize,
+    /// Move images whose data fails to decode to the blocklist, out of the
+    /// rotation. Read errors and size limits never block an image.
+    #[serde(default)]
+    pub block_failed: bool,
+}
+
+impl Default for ScrubConfig {
+    fn default() -> Self {
+        ScrubConfig {
+            interval_secs: 0,
+            batch_size: default_scrub_batch_size(),
+            block_failed: false,
+        }
+    }
+}
+
+fn default_scrub_batch_size() -> usize {
+    10
+}
+
+#[derive(Clone, Debug, Deserialize)]
+pub struct ServerConfig {
+    /// Requests still unanswered after this many seconds get a 408, 0 disables it.
+    #[serde(default = "default_request_timeout_secs")]
+    pub request_timeout_secs: u64,
+    /// Largest request body accepted, bigger ones get a 413.
+    #[serde(default = "default_max_body_bytes")]
+    pub max_body_bytes: usize,
+    /// How long clients and proxies may cache images fetched by id, 0 makes
+    /// them revalidate every time. Random picks are never cached.
+    #[serde(default = "default_image_max_age_secs")]
+    pub image_max_age_secs: u64,
+    /// Header carrying the request id, taken from the request when the client
+    /// or a proxy sent one and generated otherwise, then echoed in the response.
+    #[serde(default = "default_request_id_header")]
+    pub request_id_header: String,
+}
+
+impl ServerConfig {
+    fn image_cache_control(&self) -> HeaderValue {
+        match self.image_max_age_secs {
+            0 => HeaderValue::from_static("no-cache"),
+            max_age => HeaderValue::from_str(&format!("public, max-age={}", max_age))
+                .expect("max-age is a valid header value"),
+        }
+    }
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            request_timeout_secs: default_request_timeout_secs(),
+            max_body_bytes: default_max_body_bytes(),
+            image_max_age_secs: default_image_max_age_secs(),
+            request_id_header: default_request_id_header(),
+        }
+    }
+}
+
+fn default_request_timeout_secs() -> u64 {
+    30
+}
+
+fn default_max_body_bytes() -> usize {
+    64 * 1024
+}
+
+fn default_image_max_age_secs() -> u64 {
+    24 * 60 * 60
+}
+
+fn default_request_id_header() -> String {
+    "x-request-id".to_string()
+}
+
+/// Bounds applied before decoding, so a decompression bomb is rejected
+/// instead of exhausting memory.
+#[derive(Clone, Debug, Deserialize)]
+pub struct LimitsConfig {
+    pub max_width: Option<u32>,
+    pub max_height: Option<u32>,
+    /// Largest decoded width times height.
+    pub max_pixels: Option<u64>,
+    /// Largest buffer the decoder may allocate for the pixel data.
+    #[serde(default = "default_max_alloc_bytes")]
+    pub max_alloc_bytes: u64,
+    /// Threads of the pool that scans, hashes and decodes in parallel, unset uses
+    /// one per core.
+    pub cpu_threads: Option<usize>,
+    /// Most images decoded at the same time, unset means unlimited.
+    pub max_concurrent_decodes: Option<usize>,
+    /// How long a request waits for a decode slot before getting a 503.
+    #[serde(default = "default_decode_wait_ms")]
+    pub decode_wait_ms: u64,
+    /// Tries per file operation when the filesystem reports a transient error,
+    /// such as a timeout on a network mount.
+    #[serde(default = "default_io_attempts")]
+    pub io_attempts: u32,
+    /// Wait before the first retry, doubled before every further one.
+    #[serde(default = "default_io_retry_delay_ms")]
+    pub io_retry_delay_ms: u64,
+}
+
+impl Default for LimitsConfig {
+    fn default() -> Self {
+        LimitsConfig {
+            max_width: None,
+            max_height: None,
+            max_pixels: None,
+            max_alloc_bytes: default_max_alloc_bytes(),
+            cpu_threads: None,
+            max_concurrent_decodes: None,
+            decode_wait_ms: default_decode_wait_ms(),
+            io_attempts: default_io_attempts(),
+            io_retry_delay_ms: default_io_retry_delay_ms(),
+        }
+    }
+}
+
+impl LimitsConfig {
+    /// Runs `op` up to `io_attempts` times with exponential backoff, as long as
+    /// it fails with an error worth retrying. Sleeps, so blocking threads only.
+    fn retry_io<T>(&self, img_path: &str, op: impl FnMut() -> io::Result<T>) -> io::Result<T> {
+        self.retry(img_path, op, is_transient)
+    }
+
+    /// `retry_io` for a whole open and decode, which reads most of the file
+    /// only once the pixels are decoded.
+    fn retry_decode<T>(
+        &self,
+        img_path: &str,
+        op: impl FnMut() -> Result<T, ImageError>) -> Result<T, ImageError> {
+        self.retry(img_path, op, |e| matches!(e, ImageError::IO(e) if is_transient(e)))
+    }
+
+    fn retry<T, E: std::fmt::Display>(
+        &self,
+        img_path: &str,
+        mut op: impl FnMut() -> Result<T, E>,
+        transient: impl Fn(&E) -> bool) -> Result<T, E> {
+        let mut delay = Duration::from_millis(self.io_retry_delay_ms);
+        let mut attempt = 1;
+        loop {
+            match op() {
+                Err(e) if attempt < self.io_attempts && transient(&e) => {
+                    warn!("Retrying {} in {:?} after attempt {} failed: {}", img_path, delay, attempt, e);
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                    attempt += 1;
+                }
+                result => return result,
+            }
+        }
+    }
+
+    fn image_limits(&self) -> image::Limits {
+        let mut limits = image::Limits::default();
+        limits.max_image_width = self.max_width;
+        limits.max_image_height = self.max_height;
+        limits.max_alloc = Some(self.max_alloc_bytes);
+        limits
+    }
+
+    /// Decoders treat `max_alloc` as a hint only, so the output buffer and the
+    /// pixel count are checked here before anything is allocated.
+    fn check_decoded_size(&self, decoder: &impl ImageDecoder) -> Result<(), image::ImageError> {
+        use image::error::{LimitError, LimitErrorKind};
+
+        let (width, height) = decoder.dimensions();
+        if self.max_pixels
+            .is_some_and(|max_pixels| u64::from(width) * u64::from(height) > max_pixels) {
+            return Err(image::ImageError::Limits(
+                LimitError::from_kind(LimitErrorKind::DimensionError)));
+        }
+        if decoder.total_bytes() > self.max_alloc_bytes {
+            return Err(image::ImageError::Limits(
+                LimitError::from_kind(LimitErrorKind::InsufficientMemory)));
+        }
+        Ok(())
+    }
+}
+
+fn default_max_alloc_bytes() -> u64 {
+    512 * 1024 * 1024
+}
+
+fn default_decode_wait_ms() -> u64 {
+    5000
+}
+
+fn default_io_attempts() -> u32 {
+    3
+}
+
+fn default_io_retry_delay_ms() -> u64 {
+    100
+}
+
+#[derive(Clone, Debug, Default, Deserialize)]
+pub struct RateLimitConfig {
+    /// Sustained requests per second allowed per client IP, 0 disables limiting.
+    #[serde(default)]
+    pub requests_per_second: f64,
+    /// Short bursts allowed above the sustained rate, defaults to one second's worth.
+    pub burst: Option<f64>,
+}
+
+fn default_strip_metadata() -> bool {
+    true
+}
+
+fn default_cache_size() -> usize {
+    128
+}
+
+fn default_cache_max_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_random_attempts() -> usize {
+    3
+}
+
+fn default_near_duplicate_window() -> usize {
+    5
+}
+
+fn default_warm_concurrency() -> usize {
+    2
+}
+
+fn default_extensions() -> Vec<String> {
+    let extensions = IMAGE_EXTENSION.iter();
+    #[cfg(feature = "heif")]
+    let extensions = extensions.chain(HEIF_EXTENSION.iter());
+    extensions.map(|extension| extension.to_string()).collect()
+}
+
+#[derive(Clone, Copy, Debug, Default, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    /// Human readable lines.
+    #[default]
+    Plain,
+    /// One JSON object per line, for log pipelines that index fields.
+    Json,
+}
+
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+pub enum MediaDirs {
+    Single(String),
+    Multiple(Vec<String>),
+}
+
+impl MediaDirs {
+    fn into_vec(self) -> Vec<String> {
+        match self {
+            MediaDirs::Single(media_dir) => vec![media_dir],
+            MediaDirs::Multiple(media_dirs) => media_dirs,
+        }
+    }
+}
+
+#[derive(Debug, Deserialize)]
+pub struct MediaConfigRaw {
+    #[serde(rename = "media_dir")]
+    pub media: MediaDirs,
+    pub log_level: Option<String>,
+    #[serde(default)]
+    pub log_format: LogFormat,
+    pub network: NetworkConfigRaw,
+    pub image: ImageConfig,
+    #[serde(default)]
+    pub scan: ScanConfig,
+    #[serde(default)]
+    pub auth: AuthConfig,
+    #[serde(default)]
+    pub cors: CorsConfig,
+    #[serde(default)]
+    pub rate_limit: RateLimitConfig,
+    #[serde(default)]
+    pub api: ApiConfig,
+    #[serde(default)]
+    pub limits: LimitsConfig,
+    #[serde(default)]
+    pub server: ServerConfig,
+    #[serde(default)]
+    pub favorites: FavoritesConfig,
+    #[serde(default)]
+    pub blocklist: BlocklistConfig,
+    #[serde(default)]
+    pub push: PushConfig,
+    #[serde(default)]
+    pub scrub: ScrubConfig,
+}
+
+#[derive(Clone, Debug)]
+pub struct MediaConfig {
+    pub media: Vec<String>,
+    pub network: SocketAddr,
+    pub tls: Option<TlsConfig>,
+    pub listen_uds: Option<PathBuf>,
+    pub image: ImageConfig,
+    pub log_level: LevelFilter,
+    pub log_format: LogFormat,
+    pub scan_filter: ScanFilter,
+    /// Images are listed by this file instead of scanning when set.
+    pub manifest: Option<PathBuf>,
+    pub allow_empty: bool,
+    pub index_order: IndexOrder,
+    pub auth: AuthConfig,
+    pub cors: CorsConfig,
+    pub rate_limit: RateLimitConfig,
+    pub api: ApiConfig,
+    pub limits: LimitsConfig,
+    pub server: ServerConfig,
+    pub favorites: FavoritesConfig,
+    pub blocklist: BlocklistConfig,
+    pub push: PushConfig,
+    pub scrub: ScrubConfig,
+    /// The config file, or environment variable, that set each dotted key.
+    pub sources: BTreeMap<String, String>,
+    /// Values `new` could not use, replaced by defaults until `validate`
+    /// reports them with the rest.
+    problems: Vec<String>,
+}
+
+/// Environment variables consulted for config keys the TOML file leaves unset.
+const ENV_FALLBACKS: [(&str, &[&str]); 6] = [
+    ("NAS_MEDIA_DIR", &["media_dir"]),
+    ("NAS_LOG_LEVEL", &["log_level"]),
+    ("NAS_LOG_FORMAT", &["log_format"]),
+    ("NAS_ADDR", &["network", "addr"]),
+    ("NAS_PORT", &["network", "port"]),
+    ("NAS_RESOLUTION", &["image", "resolution"]),
+];
+
+fn env_value(var: &str, raw: &str) -> Result<toml::Value, String> {
+    let invalid = |e: &dyn std::fmt::Display| format!("Invalid value for {}: {}", var, e);
+    match var {
+        // Several media directories can be given separated by commas.
+        "NAS_MEDIA_DIR" => {
+            let dirs: Vec<toml::Value> = raw.split(',')
+                .map(|dir| toml::Value::String(dir.trim().to_string()))
+                .collect();
+            Ok(if dirs.len() == 1 { dirs[0].clone() } else { toml::Value::Array(dirs) })
+        }
+        "NAS_ADDR" => {
+            let addr: Ipv4Addr = raw.parse().map_err(|e| invalid(&e))?;
+            Ok(toml::Value::Array(
+                addr.octets().iter().map(|octet| toml::Value::Integer(*octet as i64)).collect()))
+        }
+        "NAS_PORT" | "NAS_RESOLUTION" => raw.parse::<i64>()
+            .map(toml::Value::Integer)
+            .map_err(|e| invalid(&e)),
+        _ => Ok(toml::Value::String(raw.to_string())),
+    }
+}
+
+/// Deep-merges `overlay` into `base`: tables are merged key by key, any other
+/// value replaces the earlier one. `sources` maps every key set to `file`.
+fn merge_tables(
+    base: &mut toml::Table,
+    overlay: toml::Table,
+    prefix: &str,
+    file: &str,
+    sources: &mut BTreeMap<String, String>,
+) {
+    for (key, value) in overlay {
+        let key_path = if prefix.is_empty() { key.clone() } else { format!("{}.{}", prefix, key) };
+        match value {
+            toml::Value::Table(overlay) if base.get(&key).is_none_or(toml::Value::is_table) => {
+                let base = base.entry(key)
+                    .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                    .as_table_mut()
+                    .expect("checked to be a table");
+                merge_tables(base, overlay, &key_path, file, sources);
+            }
+            value => {
+                let nested = format!("{}.", key_path);
+                sources.retain(|source_key, _| !source_key.starts_with(&nested));
+                sources.insert(key_path, file.to_string());
+                base.insert(key, value);
+            }
+        }
+    }
+}
+
+/// Fills keys missing from `table` with the `ENV_FALLBACKS` that `env` finds.
+fn apply_env_fallbacks(
+    table: &mut toml::Table,
+    sources: &mut BTreeMap<String, String>,
+    env: impl Fn(&str) -> Option<String>,
+) -> Result<(), String> {
+    for (var, key_path) in ENV_FALLBACKS {
+        let Some(raw) = env(var) else {
+            continue;
+        };
+        let (key, parents) = key_path.split_last().unwrap();
+        let mut section = &mut *table;
+        for parent in parents {
+            section = section
+                .entry(parent.to_string())
+                .or_insert_with(|| toml::Value::Table(toml::Table::new()))
+                .as_table_mut()
+                .ok_or_else(|| format!("Config key '{}' must be a table", parent))?;
+        }
+        if !section.contains_key(*key) {
+            section.insert(key.to_string(), env_value(var, &raw)?);
+            sources.insert(key_path.join("."), var.to_string());
+        }
+    }
+    Ok(())
+}
+
+/// Compares settings without a `PartialEq`, such as compiled glob sets.
+fn changed(old: &impl std::fmt::Debug, new: &impl std::fmt::Debug) -> bool {
+    format!("{:?}", old) != format!("{:?}", new)
+}
+
+impl MediaConfig {
+    /// Merges `paths` in order, later files overriding the keys they set.
+    pub fn new(paths: &[String]) -> Result<Self, String> {
+        let mut table = toml::Table::new();
+        let mut sources = BTreeMap::new();
+        for path in paths {
+            let contents = std::fs::read_to_string(path)
+                .map_err(
+                    |e| format!("Could not read config file '{}': {}", path, e))?;
+            let overlay = contents.parse::<toml::Table>()
+                .map_err(
+                    |e| format!(
+                        "Could not parse TOML from file '{}': {}", path, e))?;
+            merge_tables(&mut table, overlay, "", path, &mut sources);
+        }
+        apply_env_fallbacks(&mut table, &mut sources, |var| std::env::var(var).ok())?;
+
+        let path = match paths {
+            [] => "<environment>".to_string(),
+            paths => paths.join(", "),
+        };
+        let raw_config: MediaConfigRaw = toml::Value::Table(table).try_into()
+            .map_err(
+                |e| format!("Invalid configuration from '{}': {}", path, e))?;
+        let mut problems = Vec::new();
+        let network_socket = SocketAddr::from(
+            (raw_config.network.addr, raw_config.network.port));
+        let tls = match (raw_config.network.tls_cert, raw_config.network.tls_key) {
+            (Some(cert), Some(key)) => Some(TlsConfig { cert, key }),
+            (None, None) => None,
+            _ => {
+                problems.push("network.tls_cert and network.tls_key must be set together".to_string());
+                None
+            }
+        };
+        let log_level = match raw_config.log_level {
+            Some(level) => level.parse::<LevelFilter>().unwrap_or_else(|_| {
+                problems.push(format!("log_level '{}' is not a valid level", level));
+                LevelFilter::INFO
+            }),
+            None => LevelFilter::INFO,
+        };
+
+        let scan_filter = ScanFilter::new(&raw_config.image, &raw_config.scan, &mut problems);
+        let manifest = match (raw_config.scan.source, raw_config.scan.manifest_path) {
+            (ImageSource::Scan, _) => None,
+            (ImageSource::Manifest, Some(manifest_path)) => Some(PathBuf::from(manifest_path)),
+            (ImageSource::Manifest, None) => {
+                problems.push("scan.manifest_path must be set when scan.source is \"manifest\"".to_string());
+                None
+            }
+        };
+
+        Ok(MediaConfig {
+            media: raw_config.media.into_vec(),
+            network: network_socket,
+            tls,
+            listen_uds: raw_config.network.listen_uds.map(PathBuf::from),
+            image: raw_config.image,
+            log_level,
+            log_format: raw_config.log_format,
+            scan_filter,
+            manifest,
+            allow_empty: raw_config.scan.allow_empty,
+            index_order: raw_config.scan.index_order,
+            auth: raw_config.auth,
+            cors: raw_config.cors,
+            rate_limit: raw_config.rate_limit,
+            api: raw_config.api,
+            limits: raw_config.limits,
+            server: raw_config.server,
+            favorites: raw_config.favorites,
+            blocklist: raw_config.blocklist,
+            push: raw_config.push,
+            scrub: raw_config.scrub,
+            sources,
+            problems,
+        })
+    }
+
+    /// Reports at debug level where each configured value came from.
+    pub fn log_sources(&self) {
+        for (key, source) in &self.sources {
+            debug!("Config {} set by {}", key, source);
+        }
+    }
+
+    /// Whether the collection scanned with these settings could differ from the
+    /// one scanned with `old`, so a reload has to walk the media again.
+    pub fn changes_scan(&self, old: &MediaConfig) -> bool {
+        old.media != self.media
+            || old.manifest != self.manifest
+            || changed(&old.scan_filter, &self.scan_filter)
+            || changed(&old.index_order, &self.index_order)
+            // Perceptual hashes are only computed while near-duplicates are filtered.
+            || old.image.near_duplicate_distance.is_some() != self.image.near_duplicate_distance.is_some()
+    }
+
+    /// Settings differing from `old` that are only read at startup.
+    pub fn restart_only_changes(&self, old: &MediaConfig) -> Vec<&'static str> {
+        [
+            ("network", changed(&old.network, &self.network)),
+            ("network.tls_cert/tls_key", changed(&old.tls, &self.tls)),
+            ("network.listen_uds", old.listen_uds != self.listen_uds),
+            ("log_format", changed(&old.log_format, &self.log_format)),
+            ("image.cache_size", old.image.cache_size != self.image.cache_size),
+            ("image.cache_max_bytes", old.image.cache_max_bytes != self.image.cache_max_bytes),
+            ("image.cache_dir", old.image.cache_dir != self.image.cache_dir),
+            ("image.watermark_path", old.image.watermark_path != self.image.watermark_path),
+            ("image.seed", old.image.seed != self.image.seed),
+            ("cors", changed(&old.cors, &self.cors)),
+            ("rate_limit", changed(&old.rate_limit, &self.rate_limit)),
+            ("limits.cpu_threads", old.limits.cpu_threads != self.limits.cpu_threads),
+            ("limits.max_concurrent_decodes",
+                old.limits.max_concurrent_decodes != self.limits.max_concurrent_decodes),
+            ("server.request_timeout_secs",
+                old.server.request_timeout_secs != self.server.request_timeout_secs),
+            ("server.max_body_bytes", old.server.max_body_bytes != self.server.max_body_bytes),
+            ("server.request_id_header", old.server.request_id_header != self.server.request_id_header),
+            ("favorites.path", old.favorites.path != self.favorites.path),
+            ("blocklist.path", old.blocklist.path != self.blocklist.path),
+            ("push.interval_secs", old.push.interval_secs != self.push.interval_secs),
+            ("scrub.interval_secs", old.scrub.interval_secs != self.scrub.interval_secs),
+        ]
+            .into_iter()
+            .filter(|(_, changed)| *changed)
+            .map(|(setting, _)| setting)
+            .collect()
+    }
+
+    /// Checks the values parsing cannot catch, reporting every problem at once
+    /// rather than stopping at the first.
+    pub fn validate(&self) -> Result<(), String> {
+        let mut problems = self.problems.clone();
+        if self.media.is_empty() {
+            problems.push("media_dir must name at least one directory".to_string());
+        }
+        // A manifest lists the files itself. Otherwise one readable directory is
+        // enough, the scan warns about the others and carries on without them.
+        if self.manifest.is_none() && !self.media.is_empty() {
+            let unreadable: Vec<String> = self.media.iter()
+                .filter_map(|media_dir| fs::read_dir(media_dir).err()
+                    .map(|e| format!("'{}' ({})", media_dir, e)))
+                .collect();
+            if unreadable.len() == self.media.len() {
+                problems.push(format!("no media_dir is readable: {}", unreadable.join(", ")));
+            }
+        }
+        if let Some(manifest) = &self.manifest
+            && let Err(e) = read_manifest(manifest, &self.scan_filter) {
+            problems.push(format!("scan.manifest_path is not usable: {}", e));
+        }
+        if self.network.port() == 0 {
+            problems.push("network.port must not be 0".to_string());
+        }
+        if let Some(tls) = &self.tls {
+            for (key, file) in [("tls_cert", &tls.cert), ("tls_key", &tls.key)] {
+                if let Err(e) = File::open(file) {
+                    problems.push(format!("network.{} '{}' is not readable: {}", key, file, e));
+                }
+            }
+        }
+        if HeaderName::from_bytes(self.server.request_id_header.as_bytes()).is_err() {
+            problems.push(format!(
+                "server.request_id_header '{}' is not a valid header name", self.server.request_id_header));
+        }
+        if self.listen_uds.is_some() {
+            if cfg!(not(unix)) {
+                problems.push("network.listen_uds is only supported on Unix".to_string());
+            }
+            if self.tls.is_some() {
+                problems.push("network.listen_uds cannot be combined with tls_cert/tls_key".to_string());
+            }
+        }
+        if self.image.default_size() == (None, None) {
+            problems.push("image.resolution or image.width/image.height must be set".to_string());
+        }
+        for (key, value) in [
+            ("resolution", self.image.resolution),
+            ("width", self.image.width),
+            ("height", self.image.height),
+        ] {
+            match value {
+                Some(0) => problems.push(format!("image.{} must be greater than zero", key)),
+                Some(value) if value > MAX_RESOLUTION => {
+                    problems.push(format!("image.{} must be at most {}", key, MAX_RESOLUTION));
+                }
+                _ => {}
+            }
+        }
+        if self.api.daily_utc_offset_minutes.abs() > 14 * 60 {
+            problems.push("api.daily_utc_offset_minutes must be within 14 hours of UTC".to_string());
+        }
+        if self.limits.io_attempts == 0 {
+            problems.push("limits.io_attempts must be greater than zero".to_string());
+        }
+        if self.limits.cpu_threads == Some(0) {
+            problems.push("limits.cpu_threads must be greater than zero".to_string());
+        }
+        if self.limits.max_concurrent_decodes == Some(0) {
+            problems.push("limits.max_concurrent_decodes must be greater than zero".to_string());
+        }
+        if self.scan_filter.max_depth == Some(0) {
+            problems.push("scan.max_depth must be greater than zero".to_string());
+        }
+        if self.image.extensions.is_empty() {
+            problems.push("image.extensions must list at least one extension".to_string());
+        }
+
+        if problems.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Invalid configuration:\n  - {}", problems.join("\n  - ")))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image_config() -> ImageConfig {
+        toml::from_str("resolution = 64").unwrap()
+    }
+
+    fn scan_filter() -> ScanFilter {
+        ScanFilter::new(&image_config(), &ScanConfig::default(), &mut Vec::new())
+    }
+
+    /// Noise, so JPEG quality makes a real difference to the size.
+    fn noisy_image() -> DynamicImage {
+        let mut state = 1u32;
+        DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |_, _| {
+            let mut channel = || {
+                state = state.wrapping_mul(1_103_515_245).wrapping_add(12_345);
+                (state >> 16) as u8
+            };
+            image::Rgb([channel(), channel(), channel()])
+        }))
+    }
+
+    /// A state over `count` tiny PNGs in a fresh directory, drawing with `seed`.
+    fn seeded_state(name: &str, count: usize, seed: u64) -> MediaState {
+        let root = std::env::temp_dir().join(format!("nas_images_{}_{}", name, std::process::id()));
+        let media = root.join("media");
+        fs::create_dir_all(&media).unwrap();
+        for i in 0..count {
+            RgbImage::from_pixel(4, 4, image::Rgb([i as u8, 0, 0])).save(media.join(format!("{}.png", i))).unwrap();
+        }
+        let config_path = root.join("config.toml");
+        fs::write(&config_path, format!(
+            "media_dir = {:?}\n[network]\naddr = [127, 0, 0, 1]\nport = 3000\n[image]\nresolution = 64\nextensions = [\"png\"]\nseed = {}\n",
+            media.to_str().unwrap(), seed)).unwrap();
+        let media_config = MediaConfig::new(&[config_path.to_str().unwrap().to_string()]).unwrap();
+        let state = MediaState::new(media_config).unwrap();
+        fs::remove_dir_all(&root).unwrap();
+        state
+    }
+
+    fn random_ids(state: &MediaState) -> Vec<usize> {
+        (0..20).map(|_| state.get_random_image().unwrap().0).collect()
+    }
+
+    fn slideshow_ids(state: &MediaState) -> Vec<usize> {
+        (0..20).map(|_| state.get_next_image().unwrap().0).collect()
+    }
+
+    #[test]
+    fn same_seed_draws_same_sequence() {
+        let first = seeded_state("seed_a", 10, 7);
+        let second = seeded_state("seed_b", 10, 7);
+        let other = seeded_state("seed_c", 10, 8);
+
+        let picks = random_ids(&first);
+        assert_eq!(picks, random_ids(&second));
+        assert_ne!(picks, random_ids(&other));
+        assert_eq!(slideshow_ids(&first), slideshow_ids(&second));
+    }
+
+    #[test]
+    fn slideshow_visits_every_image_before_repeating() {
+        let state = seeded_state("slideshow", 10, 1);
+        let mut round: Vec<usize> = slideshow_ids(&state)[..10].to_vec();
+        round.sort_unstable();
+        assert_eq!(round, (0..10).collect::<Vec<_>>());
+    }
+
+    #[test]
+    fn parses_byte_ranges() {
+        assert_eq!(parse_byte_range("bytes=0-99", 1000), ByteRange::Partial(0, 99));
+        assert_eq!(parse_byte_range("bytes=900-", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_byte_range("bytes=-100", 1000), ByteRange::Partial(900, 999));
+        assert_eq!(parse_byte_range("bytes=500-5000", 1000), ByteRange::Partial(500, 999));
+        assert_eq!(parse_byte_range("bytes=-5000", 1000), ByteRange::Partial(0, 999));
+        assert_eq!(parse_byte_range("bytes=0-1,5-9", 1000), ByteRange::Full);
+        assert_eq!(parse_byte_range("bytes=1000-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("bytes=9-5", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("bytes=-0", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("bytes=-", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("items=0-9", 1000), ByteRange::Unsatisfiable);
+        assert_eq!(parse_byte_range("bytes=0-", 0), ByteRange::Unsatisfiable);
+    }
+
+    #[test]
+    fn parses_srcset_sizes() {
+        assert_eq!(parse_sizes("1024, 256,512,256").ok(), Some(vec![256, 512, 1024]));
+        assert_eq!(parse_sizes("99999").ok(), Some(vec![MAX_RESOLUTION]));
+        assert_eq!(parse_sizes("").ok(), Some(Vec::new()));
+        // Escaped commas, in either case, as URL builders send them.
+        assert_eq!(parse_sizes("256%2C512%2c1024").ok(), Some(vec![256, 512, 1024]));
+        assert_eq!(parse_sizes("256%2c%2c512").ok(), Some(vec![256, 512]));
+        assert!(parse_sizes("256%2D512").is_err());
+        assert!(parse_sizes("0").is_err());
+        assert!(parse_sizes("12px").is_err());
+        let too_many: Vec<String> = (1..=MAX_SRCSET_SIZES as u32 + 1).map(|size| size.to_string()).collect();
+        assert!(parse_sizes(&too_many.join(",")).is_err());
+    }
+
+    #[test]
+    fn merges_config_tables_recording_sources() {
+        let base: toml::Table = toml::from_str(
+            "log_level = \"info\"\n[image]\nresolution = 720\ncache_size = 128\n[cors]\nallowed_origins = [\"a\"]").unwrap();
+        let mut table = toml::Table::new();
+        let mut sources = BTreeMap::new();
+        merge_tables(&mut table, base, "", "base.toml", &mut sources);
+        let overlay: toml::Table = toml::from_str(
+            "[image]\nresolution = 1080\n[cors]\nallowed_origins = [\"b\"]").unwrap();
+        merge_tables(&mut table, overlay, "", "local.toml", &mut sources);
+
+        assert_eq!(table["image"]["resolution"].as_integer(), Some(1080));
+        // Keys the overlay leaves out survive, arrays are replaced whole.
+        assert_eq!(table["image"]["cache_size"].as_integer(), Some(128));
+        assert_eq!(table["cors"]["allowed_origins"].as_array().unwrap().len(), 1);
+        assert_eq!(table["cors"]["allowed_origins"][0].as_str(), Some("b"));
+        assert_eq!(sources["image.resolution"], "local.toml");
+        assert_eq!(sources["image.cache_size"], "base.toml");
+        assert_eq!(sources["log_level"], "base.toml");
+
+        // A scalar replacing a whole section drops the section's sources.
+        let overlay: toml::Table = toml::from_str("image = \"none\"").unwrap();
+        merge_tables(&mut table, overlay, "", "odd.toml", &mut sources);
+        assert_eq!(sources["image"], "odd.toml");
+        assert!(!sources.contains_key("image.resolution"));
+    }
+
+    #[test]
+    fn matches_etags() {
+        let etag = HeaderValue::from_static("\"abc\"");
+        let with = |value: &'static str| {
+            let mut headers = HeaderMap::new();
+            headers.insert(header::IF_NONE_MATCH, HeaderValue::from_static(value));
+            headers
+        };
+        assert!(etag_matches(&with("\"abc\""), &etag));
+        assert!(etag_matches(&with("W/\"abc\""), &etag));
+        assert!(etag_matches(&with("\"x\", \"abc\""), &etag));
+        assert!(etag_matches(&with("*"), &etag));
+        assert!(!etag_matches(&with("\"abcd\""), &etag));
+        assert!(!etag_matches(&HeaderMap::new(), &etag));
+    }
+
+    /// Serves `bytes` until `fail_at`, then fails every read like a dropped mount.
+    struct FailingReader {
+        bytes: Cursor<Vec<u8>>,
+        fail_at: u64,
+    }
+
+    impl Read for FailingReader {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let left = self.fail_at.saturating_sub(self.bytes.position()) as usize;
+            if left == 0 {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "mount went away"));
+            }
+            let len = buf.len().min(left);
+            Read::read(&mut self.bytes, &mut buf[..len])
+        }
+    }
+
+    impl io::Seek for FailingReader {
+        fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+            io::Seek::seek(&mut self.bytes, pos)
+        }
+    }
+
+    fn png_bytes() -> Vec<u8> {
+        let mut bytes = Vec::new();
+        noisy_image().write_to(&mut Cursor::new(&mut bytes), ImageFormat::Png).unwrap();
+        bytes
+    }
+
+    #[test]
+    fn read_failing_mid_stream_is_never_corrupt() {
+        let reader = FailingReader { bytes: Cursor::new(png_bytes()), fail_at: 200 };
+        let reader = ImageReader::with_format(BufReader::new(reader), ImageFormat::Png);
+        let e = decode_reader(reader, &LimitsConfig::default()).unwrap_err();
+        assert!(matches!(e, ImageError::IO(_)), "got {}", e);
+        assert!(!e.is_corrupt());
+    }
+
+    #[test]
+    fn truncated_file_is_corrupt() {
+        let mut bytes = png_bytes();
+        bytes.truncate(200);
+        let reader = ImageReader::with_format(Cursor::new(bytes), ImageFormat::Png);
+        let e = decode_reader(reader, &LimitsConfig::default()).unwrap_err();
+        assert!(e.is_corrupt(), "got {}", e);
+    }
+
+    #[test]
+    fn only_mount_hiccups_are_transient() {
+        for kind in [io::ErrorKind::TimedOut, io::ErrorKind::WouldBlock, io::ErrorKind::Interrupted,
+            io::ErrorKind::ResourceBusy, io::ErrorKind::StaleNetworkFileHandle] {
+            assert!(is_transient(&io::Error::from(kind)), "{:?}", kind);
+        }
+        for kind in [io::ErrorKind::NotFound, io::ErrorKind::PermissionDenied,
+            io::ErrorKind::UnexpectedEof, io::ErrorKind::InvalidData] {
+            assert!(!is_transient(&io::Error::from(kind)), "{:?}", kind);
+        }
+    }
+
+    fn retry_limits(io_attempts: u32, io_retry_delay_ms: u64) -> LimitsConfig {
+        LimitsConfig { io_attempts, io_retry_delay_ms, ..LimitsConfig::default() }
+    }
+
+    #[test]
+    fn retry_io_backs_off_until_attempts_run_out() {
+        let limits = retry_limits(4, 5);
+        let mut calls = 0;
+        let started = Instant::now();
+        let result: io::Result<()> = limits.retry_io("x", || {
+            calls += 1;
+            Err(io::ErrorKind::TimedOut.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 4);
+        // Three waits doubling from 5 ms.
+        assert!(started.elapsed() >= Duration::from_millis(5 + 10 + 20));
+    }
+
+    #[test]
+    fn retry_io_stops_at_success_or_final_errors() {
+        let limits = retry_limits(3, 1);
+        let mut calls = 0;
+        let result = limits.retry_io("x", || {
+            calls += 1;
+            if calls < 2 { Err(io::ErrorKind::Interrupted.into()) } else { Ok(calls) }
+        });
+        assert_eq!(result.unwrap(), 2);
+
+        let mut calls = 0;
+        let result: io::Result<()> = limits.retry_io("x", || {
+            calls += 1;
+            Err(io::ErrorKind::NotFound.into())
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 1);
+
+        // A single attempt never retries.
+        let mut calls = 0;
+        let _ = retry_limits(1, 1).retry_io("x", || -> io::Result<()> {
+            calls += 1;
+            Err(io::ErrorKind::TimedOut.into())
+        });
+        assert_eq!(calls, 1);
+    }
+
+    #[test]
+    fn retry_decode_retries_reads_but_not_corrupt_data() {
+        let limits = retry_limits(3, 1);
+        let mut calls = 0;
+        let result: Result<(), ImageError> = limits.retry_decode("x", || {
+            calls += 1;
+            Err(ImageError::IO(io::ErrorKind::TimedOut.into()))
+        });
+        assert!(result.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        let truncated = ImageReader::with_format(Cursor::new(png_bytes()[..200].to_vec()), ImageFormat::Png);
+        let mut truncated = Some(truncated);
+        let result = limits.retry_decode("x", || {
+            calls += 1;
+            decode_reader(truncated.take().expect("decoded only once"), &limits)
+        });
+        assert!(result.is_err_and(|e| e.is_corrupt()));
+        assert_eq!(calls, 1);
+    }
+
+    fn cache_entry(path: &str, size: usize) -> ((String, ThumbnailSpec), Thumbnail) {
+        let key = (path.to_string(), image_config().default_spec(OutputFormat::Jpeg));
+        (key, Thumbnail { bytes: Bytes::from(vec![0; size]), quality: None })
+    }
+
+    #[test]
+    fn thumbnail_cache_stays_within_its_byte_budget() {
+        let mut cache = ThumbnailCache::new(NonZeroUsize::new(10).unwrap(), 100);
+        for (path, size) in [("a", 40), ("b", 40), ("c", 40)] {
+            let (key, thumbnail) = cache_entry(path, size);
+            cache.put(key, thumbnail);
+        }
+        // "a" was least recently used and made room for "c".
+        assert_eq!(cache.bytes, 80);
+        assert!(cache.get(&cache_entry("a", 0).0).is_none());
+        assert!(cache.get(&cache_entry("c", 0).0).is_some());
+
+        // Larger than the whole budget, never kept.
+        let (key, thumbnail) = cache_entry("huge", 101);
+        cache.put(key.clone(), thumbnail);
+        assert!(cache.get(&key).is_none());
+        assert_eq!(cache.bytes, 80);
+
+        // Replacing an entry only counts the new bytes.
+        let (key, thumbnail) = cache_entry("b", 10);
+        cache.put(key, thumbnail);
+        assert_eq!(cache.bytes, 50);
+
+        cache.remove_path("c");
+        assert_eq!(cache.bytes, 10);
+        cache.clear();
+        assert_eq!(cache.bytes, 0);
+    }
+
+    #[test]
+    fn thumbnail_cache_counts_entries_pushed_out_by_capacity() {
+        let mut cache = ThumbnailCache::new(NonZeroUsize::new(2).unwrap(), 1000);
+        for path in ["a", "b", "c"] {
+            let (key, thumbnail) = cache_entry(path, 10);
+            cache.put(key, thumbnail);
+        }
+        assert_eq!(cache.entries.len(), 2);
+        assert_eq!(cache.bytes, 20);
+    }
+
+    fn negotiated(accept: &'static str) -> OutputFormat {
+        let mut headers = HeaderMap::new();
+        headers.insert(header::ACCEPT, HeaderValue::from_static(accept));
+        OutputFormat::negotiate(&headers)
+    }
+
+    #[test]
+    fn negotiates_output_format_from_accept() {
+        // What browsers with AVIF support send.
+        assert_eq!(negotiated("image/avif,image/webp,image/apng,image/*,*/*;q=0.8"), OutputFormat::Avif);
+        assert_eq!(negotiated("IMAGE/AVIF"), OutputFormat::Avif);
+        // Lossless WebP is never picked on its own.
+        assert_eq!(negotiated("image/webp,*/*"), OutputFormat::Jpeg);
+        // Refused with q=0.
+        assert_eq!(negotiated("image/avif;q=0, image/*"), OutputFormat::Jpeg);
+        assert_eq!(negotiated("image/avif;q=0"), OutputFormat::Jpeg);
+        // Ordered by q, with ties going to AVIF.
+        assert_eq!(negotiated("image/avif;q=0.5, image/jpeg"), OutputFormat::Jpeg);
+        assert_eq!(negotiated("image/avif;q=0.9, image/*;q=0.5"), OutputFormat::Avif);
+        assert_eq!(negotiated("image/avif;q=0.8, image/jpeg;q=0.8"), OutputFormat::Avif);
+        // Wildcards alone and missing headers get JPEG.
+        assert_eq!(negotiated("*/*"), OutputFormat::Jpeg);
+        assert_eq!(negotiated("image/*"), OutputFormat::Jpeg);
+        assert_eq!(OutputFormat::negotiate(&HeaderMap::new()), OutputFormat::Jpeg);
+        assert_eq!(negotiated("not a media type;;q=x"), OutputFormat::Jpeg);
+    }
+
+    #[test]
+    fn parses_unix_and_rfc3339_timestamps() {
+        let may_first = UNIX_EPOCH + Duration::from_secs(1_714_521_600);
+        assert_eq!(parse_timestamp("1714521600").ok(), Some(may_first));
+        assert_eq!(parse_timestamp("0").ok(), Some(UNIX_EPOCH));
+        assert_eq!(parse_timestamp("2024-05-01T00:00:00Z").ok(), Some(may_first));
+        assert_eq!(parse_timestamp("2024-05-01T02:00:00+02:00").ok(), Some(may_first));
+        assert_eq!(parse_timestamp("1969-12-31T23:59:59Z").ok(), UNIX_EPOCH.checked_sub(Duration::from_secs(1)));
+
+        for malformed in ["", "yesterday", "-1", "1.5", "2024-05-01", "2024-05-01 00:00:00", "2024-05-01T00:00:00"] {
+            assert!(matches!(parse_timestamp(malformed), Err(ImageError::BadRequest(_))), "{}", malformed);
+        }
+        for out_of_range in [&u64::MAX.to_string(), "2024-13-01T00:00:00Z", "2024-02-30T00:00:00Z", "2024-05-01T24:00:00Z"] {
+            assert!(matches!(parse_timestamp(out_of_range), Err(ImageError::BadRequest(_))), "{}", out_of_range);
+        }
+    }
+
+    fn thumb_spec(query: &str, config: &str) -> Option<ThumbnailSpec> {
+        let uri: Uri = format!("/thumbnail?{}", query).parse().unwrap();
+        let Query(params) = Query::<ThumbParams>::try_from_uri(&uri).unwrap();
+        params.spec(&toml::from_str(config).unwrap(), &HeaderMap::new()).ok()
+    }
+
+    fn spec_size(query: &str, config: &str) -> (Option<u32>, Option<u32>) {
+        let spec = thumb_spec(query, config).unwrap();
+        (spec.width, spec.height)
+    }
+
+    #[test]
+    fn thumbnail_size_precedence() {
+        let square = "resolution = 64";
+        let sized = "resolution = 64\nwidth = 320\nheight = 180";
+        let wide = "resolution = 64\nwidth = 320";
+
+        // Configured sizes, width and height winning over resolution.
+        assert_eq!(spec_size("", square), (Some(64), Some(64)));
+        assert_eq!(spec_size("", sized), (Some(320), Some(180)));
+        assert_eq!(spec_size("", wide), (Some(320), None));
+        // ?resolution= wins over every configured size.
+        for config in [square, sized, wide] {
+            assert_eq!(spec_size("resolution=100", config), (Some(100), Some(100)));
+        }
+        // ?w= and ?h= win over ?resolution= and the config, a missing side
+        // following the aspect ratio.
+        for config in [square, sized, wide] {
+            assert_eq!(spec_size("w=50", config), (Some(50), None));
+            assert_eq!(spec_size("h=40&resolution=100", config), (None, Some(40)));
+            assert_eq!(spec_size("w=50&h=40&resolution=100", config), (Some(50), Some(40)));
+        }
+        assert_eq!(spec_size("w=100000&resolution=100000", square), (Some(MAX_RESOLUTION), None));
+        assert_eq!(spec_size("resolution=100000", square), (Some(MAX_RESOLUTION), Some(MAX_RESOLUTION)));
+        for zero in ["resolution=0", "w=0", "h=0", "w=50&h=0"] {
+            assert!(thumb_spec(zero, square).is_none(), "{}", zero);
+        }
+    }
+
+    #[test]
+    fn thumbnail_byte_budget_precedence() {
+        let budget = |query: &str, config: &str| thumb_spec(query, config).unwrap().max_bytes;
+        let unbudgeted = "resolution = 64";
+        let budgeted = "resolution = 64\nmax_bytes = 20000";
+
+        assert_eq!(budget("", unbudgeted), None);
+        assert_eq!(budget("", budgeted), Some(20_000));
+        // ?max_bytes= wins over the config.
+        assert_eq!(budget("max_bytes=5000", unbudgeted), Some(5_000));
+        assert_eq!(budget("max_bytes=5000", budgeted), Some(5_000));
+        // Only JPEG has a budget, whoever set it.
+        for config in [unbudgeted, budgeted] {
+            assert_eq!(budget("format=png&max_bytes=5000", config), None);
+            assert_eq!(budget("format=avif", config), None);
+            assert_eq!(budget("format=jpeg&max_bytes=5000&w=50", config), Some(5_000));
+        }
+        assert!(thumb_spec("max_bytes=0", budgeted).is_none());
+
+        // The rest of the spec falls back to the config, then the defaults.
+        let spec = thumb_spec("", "resolution = 64\nfilter = \"nearest\"\nprogressive = true").unwrap();
+        assert_eq!(spec, ImageConfig {
+            filter: ResizeFilter::Nearest,
+            progressive: true,
+            ..image_config()
+        }.default_spec(OutputFormat::Jpeg));
+        assert_eq!(thumb_spec("quality=0", unbudgeted).unwrap().quality, 1);
+        assert_eq!(thumb_spec("quality=500", unbudgeted).unwrap().quality, 100);
+        assert!(!thumb_spec("format=png&progressive=true", unbudgeted).unwrap().progressive);
+        assert!(!thumb_spec("progressive=false", "resolution = 64\nprogressive = true").unwrap().progressive);
+    }
+
+    #[test]
+    fn classifies_aspect_with_square_tolerance() {
+        assert_eq!(Aspect::of(1920, 1080), Aspect::Landscape);
+        assert_eq!(Aspect::of(1080, 1920), Aspect::Portrait);
+        assert_eq!(Aspect::of(500, 500), Aspect::Square);
+        // Up to 5% longer either way is still square.
+        assert_eq!(Aspect::of(105, 100), Aspect::Square);
+        assert_eq!(Aspect::of(100, 105), Aspect::Square);
+        assert_eq!(Aspect::of(2100, 2000), Aspect::Square);
+        assert_eq!(Aspect::of(2101, 2000), Aspect::Landscape);
+        assert_eq!(Aspect::of(2000, 2101), Aspect::Portrait);
+        assert_eq!(Aspect::of(106, 100), Aspect::Landscape);
+        assert_eq!(Aspect::of(100, 106), Aspect::Portrait);
+        // Zero sides and extremes neither divide by zero nor overflow.
+        assert_eq!(Aspect::of(0, 0), Aspect::Square);
+        assert_eq!(Aspect::of(1, 0), Aspect::Landscape);
+        assert_eq!(Aspect::of(0, 1), Aspect::Portrait);
+        assert_eq!(Aspect::of(1, 1), Aspect::Square);
+        assert_eq!(Aspect::of(u32::MAX, u32::MAX), Aspect::Square);
+        assert_eq!(Aspect::of(u32::MAX, 1), Aspect::Landscape);
+    }
+
+    #[test]
+    fn env_fills_missing_keys_without_overriding_the_file() {
+        let env = |var: &str| match var {
+            "NAS_MEDIA_DIR" => Some("/env/a, /env/b".to_string()),
+            "NAS_PORT" => Some("9000".to_string()),
+            "NAS_RESOLUTION" => Some("512".to_string()),
+            "NAS_LOG_LEVEL" => Some("debug".to_string()),
+            _ => None,
+        };
+        let mut table: toml::Table = toml::from_str(
+            "log_level = \"warn\"\n[image]\nresolution = 256\n").unwrap();
+        let mut sources = BTreeMap::from([
+            ("log_level".to_string(), "file.toml".to_string()),
+            ("image.resolution".to_string(), "file.toml".to_string()),
+        ]);
+        apply_env_fallbacks(&mut table, &mut sources, env).unwrap();
+
+        // Keys the file set are left alone, even when the variable is set.
+        assert_eq!(table["log_level"].as_str(), Some("warn"));
+        assert_eq!(table["image"]["resolution"].as_integer(), Some(256));
+        assert_eq!(sources["log_level"], "file.toml");
+        assert_eq!(sources["image.resolution"], "file.toml");
+        // Missing keys, and the tables holding them, come from the environment.
+        assert_eq!(table["media_dir"], toml::Value::Array(vec!["/env/a".into(), "/env/b".into()]));
+        assert_eq!(table["network"]["port"].as_integer(), Some(9000));
+        assert_eq!(sources["media_dir"], "NAS_MEDIA_DIR");
+        assert_eq!(sources["network.port"], "NAS_PORT");
+        // Unset variables add nothing.
+        assert!(!table.contains_key("log_format"));
+        assert!(!table["network"].as_table().unwrap().contains_key("addr"));
+
+        let mut table = toml::Table::new();
+        let bad_port = |var: &str| (var == "NAS_PORT").then(|| "http".to_string());
+        assert!(apply_env_fallbacks(&mut table, &mut BTreeMap::new(), bad_port).is_err());
+        let mut table: toml::Table = toml::from_str("network = 1").unwrap();
+        assert!(apply_env_fallbacks(&mut table, &mut BTreeMap::new(), env).is_err());
+    }
+
+    #[test]
+    fn daily_image_turns_over_at_local_midnight() {
+        let at = |day: u64, hour: u64, minute: u64, second: u64| {
+            UNIX_EPOCH + Duration::from_secs(((day * 24 + hour) * 60 + minute) * 60 + second)
+        };
+        // At UTC, the day turns over at midnight UTC.
+        assert_eq!(day_at(at(19_844, 23, 59, 59), 0), 19_844);
+        assert_eq!(day_at(at(19_845, 0, 0, 0), 0), 19_845);
+        // East of UTC it turns over before midnight UTC, west of UTC after.
+        assert_eq!(day_at(at(19_844, 22, 59, 59), 60), 19_844);
+        assert_eq!(day_at(at(19_844, 23, 0, 0), 60), 19_845);
+        assert_eq!(day_at(at(19_845, 4, 59, 59), -5 * 60), 19_844);
+        assert_eq!(day_at(at(19_845, 5, 0, 0), -5 * 60), 19_845);
+        assert_eq!(day_at(at(19_844, 10, 15, 0), 14 * 60), 19_845);
+        assert_eq!(day_at(at(19_844, 10, 15, 0), -14 * 60), 19_843);
+        // Times before the epoch, or offsets reaching before it, are day zero.
+        assert_eq!(day_at(UNIX_EPOCH, -60), 0);
+        assert_eq!(day_at(UNIX_EPOCH - Duration::from_secs(1), 0), 0);
+
+        // The pick only changes with the day.
+        let state = seeded_state("daily", 10, 1);
+        let first = state.get_daily_image(19_844).unwrap().0;
+        assert_eq!(state.get_daily_image(19_844).unwrap().0, first);
+        let picks: HashSet<usize> = (19_844..19_864)
+            .map(|day| state.get_daily_image(day).unwrap().0)
+            .collect();
+        assert!(picks.len() > 1);
+    }
+
+    #[test]
+    fn validate_reports_every_problem_at_once() {
+        let root = std::env::temp_dir().join(format!("nas_images_validate_{}", std::process::id()));
+        fs::create_dir_all(&root).unwrap();
+        let load = |name: &str, contents: &str| {
+            let path = root.join(name);
+            fs::write(&path, contents).unwrap();
+            MediaConfig::new(&[path.to_str().unwrap().to_string()])
+        };
+
+        let config = load("bad.toml", &format!(
+            "media_dir = {:?}\nlog_level = \"loud\"\n\
+             [network]\naddr = [127, 0, 0, 1]\nport = 0\ntls_cert = \"cert.pem\"\n\
+             [image]\nresolution = 64\n\
+             [scan]\nsource = \"manifest\"\ninclude = [\"a[\"]\nexclude = [\"**/ok\", \"b{{\"]\n",
+            root.to_str().unwrap())).unwrap();
+        let report = config.validate().unwrap_err();
+        for problem in [
+            "log_level 'loud'",
+            "network.tls_cert and network.tls_key must be set together",
+            "scan.include pattern 'a['",
+            "scan.exclude pattern 'b{'",
+            "scan.manifest_path must be set",
+            "network.port must not be 0",
+        ] {
+            assert!(report.contains(problem), "{} missing from {}", problem, report);
+        }
+        assert!(!report.contains("**/ok"));
+
+        // A manifest that cannot be read is reported too.
+        let config = load("manifest.toml", &format!(
+            "media_dir = {:?}\n[network]\naddr = [127, 0, 0, 1]\nport = 3000\n[image]\nresolution = 64\n[scan]\nsource = \"manifest\"\nmanifest_path = {:?}\n",
+            root.to_str().unwrap(), root.join("missing.txt").to_str().unwrap())).unwrap();
+        assert!(config.validate().unwrap_err().contains("scan.manifest_path is not usable"));
+        fs::write(root.join("missing.txt"), "a.png\n").unwrap();
+        assert!(config.validate().is_ok());
+
+        // Values that do not parse at all still stop at once.
+        assert!(load("types.toml", "media_dir = 1").is_err());
+        fs::remove_dir_all(&root).unwrap();
+    }
+
+    fn no_metadata() -> SourceMetadata {
+        SourceMetadata { exif: None, icc: None }
+    }
+
+    #[test]
+    fn encode_image_writes_each_format() {
+        let img = noisy_image();
+        for format in OutputFormat::ALL {
+            let spec = image_config().default_spec(format);
+            let thumbnail = encode_image(&img, spec, no_metadata()).unwrap();
+            let bytes = &thumbnail.bytes[..];
+            let magic_ok = match format {
+                OutputFormat::Jpeg => bytes.starts_with(&[0xff, 0xd8, 0xff]),
+                OutputFormat::Png => bytes.starts_with(b"\x89PNG\r\n\x1a\n"),
+                OutputFormat::Webp => bytes.starts_with(b"RIFF") && &bytes[8..12] == b"WEBP",
+                OutputFormat::Avif => &bytes[4..12] == b"ftypavif",
+            };
+            assert!(magic_ok, "{:?} output starts with {:02x?}", format, &bytes[..12]);
+            assert_eq!(image::guess_format(bytes).unwrap(), format.image_format());
+            assert_eq!(format.content_type(), format.image_format().to_mime_type());
+            assert_eq!(thumbnail.quality, None);
+        }
+    }
+
+    #[test]
+    fn encode_image_writes_progressive_jpeg() {
+        let spec = ThumbnailSpec { progressive: true, ..image_config().default_spec(OutputFormat::Jpeg) };
+        let thumbnail = encode_image(&noisy_image(), spec, no_metadata()).unwrap();
+        // Progressive JPEGs start their frame with SOF2 rather than SOF0.
+        assert!(thumbnail.bytes.windows(2).any(|marker| marker == [0xff, 0xc2]));
+        assert!(!thumbnail.bytes.windows(2).any(|marker| marker == [0xff, 0xc0]));
+    }
+
+    #[test]
+    fn encode_jpeg_steps_quality_down_to_fit_max_bytes() {
+        let img = noisy_image();
+        let (full, full_quality) = encode_jpeg(&img, &no_metadata(), 90, None, false).unwrap();
+        assert_eq!(full_quality, 90);
+
+        let budget = full.len() as u64 / 2;
+        let (fitted, quality) = encode_jpeg(&img, &no_metadata(), 90, Some(budget), false).unwrap();
+        assert!((MIN_BUDGET_QUALITY..90).contains(&quality));
+        assert!(fitted.len() as u64 <= budget || quality == MIN_BUDGET_QUALITY);
+
+        // A budget nothing fits stops at the floor and returns that attempt.
+        let (_, floor) = encode_jpeg(&img, &no_metadata(), 90, Some(1), false).unwrap();
+        assert_eq!(floor, MIN_BUDGET_QUALITY);
+
+        // encode_image reports the quality a budget settled on.
+        let spec = ThumbnailSpec {
+            quality: 90,
+            max_bytes: Some(budget),
+            ..image_config().default_spec(OutputFormat::Jpeg)
+        };
+        let thumbnail = encode_image(&img, spec, no_metadata()).unwrap();
+        assert_eq!(thumbnail.quality, Some(quality));
+    }
+
+    /// Times a scan of a few thousand generated files on one thread against
+    /// eight. Run with `cargo test --release scan_speedup -- --ignored --nocapture`.
+    #[test]
+    #[ignore]
+    fn scan_speedup() {
+        let root = std::env::temp_dir().join(format!("nas_images_scan_{}", std::process::id()));
+        for dir in 0..40 {
+            let nested = root.join(format!("album{}", dir)).join("raw");
+            fs::create_dir_all(&nested).unwrap();
+            for file in 0..50 {
+                fs::write(root.join(format!("album{}", dir)).join(format!("{}.jpg", file)), b"").unwrap();
+                fs::write(nested.join(format!("{}.png", file)), b"").unwrap();
+            }
+        }
+
+        let filter = scan_filter();
+        let scan = |threads: usize| {
+            let pool = rayon::ThreadPoolBuilder::new().num_threads(threads).build().unwrap();
+            let started = Instant::now();
+            let found = pool.install(|| find_absolute_image_path(&root, &filter, 0)).unwrap();
+            (found, started.elapsed())
+        };
+        // Warm the dentry cache so neither run pays for the first reads.
+        scan(1);
+        let (sequential, sequential_time) = scan(1);
+        let (parallel, parallel_time) = scan(8);
+        fs::remove_dir_all(&root).unwrap();
+
+        assert_eq!(sequential.len(), 4000);
+        assert_eq!(sequential, parallel);
+        println!("Scanned 4000 files in {:?} on 1 thread, {:?} on 8, {:.2}x",
+            sequential_time, parallel_time,
+            sequential_time.as_secs_f64() / parallel_time.as_secs_f64());
     }
 }
@@ -0,0 +1,77 @@
+use std::collections::BTreeSet;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+use std::sync::RwLock;
+
+use tracing::info;
+
+/// A set of image paths, optionally mirrored to a JSON file so it survives
+/// restarts. Paths rather than ids are stored because ids shift on rescans.
+pub struct PathSet {
+    paths: RwLock<BTreeSet<String>>,
+    file: Option<PathBuf>,
+}
+
+impl PathSet {
+    /// Loads the set from `file`, a missing file starts an empty set.
+    pub fn load(file: Option<PathBuf>) -> Result<Self, String> {
+        let paths = match &file {
+            Some(file) => match fs::read_to_string(file) {
+                Ok(contents) => {
+                    let paths: BTreeSet<String> = serde_json::from_str(&contents)
+                        .map_err(|e| format!("Invalid path list '{}': {}", file.display(), e))?;
+                    info!("Loaded {} paths from {}", paths.len(), file.display());
+                    paths
+                }
+                Err(e) if e.kind() == io::ErrorKind::NotFound => BTreeSet::new(),
+                Err(e) => return Err(format!("Failed to read '{}': {}", file.display(), e)),
+            },
+            None => BTreeSet::new(),
+        };
+        Ok(PathSet { paths: RwLock::new(paths), file })
+    }
+
+    pub fn contains(&self, path: &str) -> bool {
+        self.paths.read().unwrap().contains(path)
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.paths.read().unwrap().is_empty()
+    }
+
+    pub fn paths(&self) -> Vec<String> {
+        self.paths.read().unwrap().iter().cloned().collect()
+    }
+
+    /// Adds `path` and persists the set, returning whether it was new.
+    pub fn insert(&self, path: String) -> io::Result<bool> {
+        let mut paths = self.paths.write().unwrap();
+        if !paths.insert(path) {
+            return Ok(false);
+        }
+        self.save(&paths)?;
+        Ok(true)
+    }
+
+    /// Removes `path` and persists the set, returning whether it was present.
+    pub fn remove(&self, path: &str) -> io::Result<bool> {
+        let mut paths = self.paths.write().unwrap();
+        if !paths.remove(path) {
+            return Ok(false);
+        }
+        self.save(&paths)?;
+        Ok(true)
+    }
+
+    fn save(&self, paths: &BTreeSet<String>) -> io::Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let contents = serde_json::to_string_pretty(paths).map_err(io::Error::other)?;
+        // Write next to the target and rename, a crash never leaves a truncated list.
+        let partial = file.with_extension("partial");
+        fs::write(&partial, contents)?;
+        fs::rename(&partial, file)
+    }
+}
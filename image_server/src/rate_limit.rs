@@ -0,0 +1,94 @@
+use std::collections::HashMap;
+use std::net::IpAddr;
+use std::sync::Mutex;
+use std::time::Instant;
+
+use crate::RateLimitConfig;
+
+/// Above this many tracked clients, buckets that have refilled are dropped.
+const MAX_TRACKED_CLIENTS: usize = 10_000;
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+/// Token bucket per client address, refilled at `requests_per_second` up to
+/// `burst` tokens.
+pub struct RateLimiter {
+    rate: f64,
+    burst: f64,
+    buckets: Mutex<HashMap<IpAddr, Bucket>>,
+}
+
+impl RateLimiter {
+    pub fn new(config: &RateLimitConfig) -> Option<Self> {
+        if config.requests_per_second <= 0.0 {
+            return None;
+        }
+        Some(RateLimiter {
+            rate: config.requests_per_second,
+            burst: config.burst.unwrap_or(config.requests_per_second).max(1.0),
+            buckets: Mutex::new(HashMap::new()),
+        })
+    }
+
+    /// Takes a token for `ip`, returning false when the client is over its limit.
+    pub fn check(&self, ip: IpAddr) -> bool {
+        let now = Instant::now();
+        let mut buckets = self.buckets.lock().unwrap();
+
+        if buckets.len() > MAX_TRACKED_CLIENTS {
+            let (rate, burst) = (self.rate, self.burst);
+            buckets.retain(|_, bucket| {
+                bucket.tokens + now.duration_since(bucket.updated).as_secs_f64() * rate < burst
+            });
+        }
+
+        let bucket = buckets.entry(ip).or_insert(Bucket { tokens: self.burst, updated: now });
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * self.rate).min(self.burst);
+        bucket.updated = now;
+
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            true
+        } else {
+            false
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    fn limiter(requests_per_second: f64, burst: Option<f64>) -> Option<RateLimiter> {
+        RateLimiter::new(&RateLimitConfig { requests_per_second, burst })
+    }
+
+    #[test]
+    fn zero_rate_disables_limiting() {
+        assert!(limiter(0.0, Some(5.0)).is_none());
+    }
+
+    #[test]
+    fn allows_a_burst_per_client() {
+        // Slow enough that no token comes back while the test runs.
+        let limiter = limiter(0.001, Some(3.0)).unwrap();
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        let other = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 2));
+        assert!((0..3).all(|_| limiter.check(client)));
+        assert!(!limiter.check(client));
+        assert!(limiter.check(other));
+    }
+
+    #[test]
+    fn burst_defaults_to_one_second_of_requests() {
+        let limiter = limiter(2.0, None).unwrap();
+        let client = IpAddr::V4(Ipv4Addr::new(10, 0, 0, 1));
+        assert!(limiter.check(client) && limiter.check(client));
+        assert!(!limiter.check(client));
+    }
+}
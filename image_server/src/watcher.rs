@@ -0,0 +1,59 @@
+use std::fs;
+use std::sync::Arc;
+
+use tracing::{error, warn};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+
+use crate::{
+    depth_below_root, find_absolute_image_path, get_canonical_path_if_image, is_ignored_below_root,
+    MediaState,
+};
+
+pub fn watch_media(state: Arc<MediaState>) -> notify::Result<RecommendedWatcher> {
+    let handler_state = state.clone();
+    let mut watcher = notify::recommended_watcher(move |result: notify::Result<Event>| {
+        match result {
+            Ok(event) => handle_event(&handler_state, event),
+            Err(e) => error!("Media watcher error: {}", e),
+        }
+    })?;
+    for media_dir in &state.media_config().media {
+        // Watch the canonical directory so event paths line up with the scanned ones.
+        let watched = fs::canonicalize(media_dir)
+            .map_err(notify::Error::from)
+            .and_then(|canonical| watcher.watch(&canonical, RecursiveMode::Recursive));
+        if let Err(e) = watched {
+            error!("Failed to watch media directory {}: {}", media_dir, e);
+        }
+    }
+    Ok(watcher)
+}
+
+fn handle_event(state: &MediaState, event: Event) {
+    if matches!(event.kind, EventKind::Access(_)) {
+        return;
+    }
+
+    let filter = &state.media_config().scan_filter;
+    let roots = state.index.read().unwrap().roots.clone();
+    for path in &event.paths {
+        // Removals still go through, an ignored path was never added anyway.
+        if path.exists() && is_ignored_below_root(&roots, path, filter) {
+            continue;
+        }
+        let depth = depth_below_root(&roots, path);
+        if path.is_dir() {
+            match find_absolute_image_path(path, filter, depth) {
+                Ok(found) => found.into_iter().for_each(|img_path| state.add_image(img_path)),
+                Err(e) => warn!("Could not scan new directory {:?}: {}", path, e),
+            }
+        } else if !filter.scans_depth(depth.saturating_sub(1)) {
+            continue;
+        } else if let Some(img_path) = get_canonical_path_if_image(path, filter) {
+            state.invalidate_thumbnails(&img_path);
+            state.add_image(img_path);
+        } else if !path.exists() {
+            state.remove_images_under(path);
+        }
+    }
+}